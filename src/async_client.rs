@@ -0,0 +1,886 @@
+use crate::config::{ClientConfig, RetryPolicy};
+use crate::msg::check_txn_status_response::Status;
+use crate::msg::{
+    key_error, CheckTxnStatusRequest, CommitRequest, CommitResponse, GetRequest, KeyError, KvPair,
+    LockInfo, NotLeader, Op, PrewriteRequest, PrewriteResponse, ResolveLockRequest,
+    RollbackRequest, ScanRequest, TimestampRequest, TimestampResponse, TxnHeartBeatRequest,
+    TxnHeartBeatResponse,
+};
+use crate::service::{TSOClient, TransactionClient};
+use crate::{TimeStamp, Write};
+
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Loop};
+use futures::sync::oneshot;
+use futures::Future;
+use futures_timer::Delay;
+use labrpc::*;
+
+impl Write {
+    fn to_msg(&self) -> crate::msg::Write {
+        crate::msg::Write {
+            key: self.0.clone(),
+            value: self.1.clone(),
+            op: self.2 as i32,
+        }
+    }
+}
+
+// LockResolver cleans up the locks of other transactions that block reads
+// and prewrites.
+#[derive(Clone)]
+pub struct LockResolver {
+    txn_client: TransactionClient,
+}
+
+impl LockResolver {
+    pub fn new(txn_client: TransactionClient) -> LockResolver {
+        LockResolver { txn_client }
+    }
+
+    // Commits or rolls back the locks of the transaction that left `lock`,
+    // depending on the state of its primary. These are `lock` and, if the
+    // primary lock was still there, the secondaries it lists. Resolves to
+    // the milliseconds until the primary lock expires if it is still alive,
+    // zero once it is gone.
+    pub fn resolve(
+        &self,
+        lock: &LockInfo,
+        caller_start_ts: TimeStamp,
+        current_ts: TimeStamp,
+    ) -> RpcFuture<u64> {
+        let txn_client = self.txn_client.clone();
+        let lock_ts = lock.start_ts;
+        let key = lock.key.clone();
+        let status = self.txn_client.check_txn_status(&CheckTxnStatusRequest {
+            primary_key: lock.primary.clone(),
+            lock_ts,
+            caller_start_ts: caller_start_ts.into(),
+            current_ts: current_ts.into(),
+        });
+        Box::new(status.and_then(move |status| -> RpcFuture<u64> {
+            let commit_ts = match Status::from_i32(status.status) {
+                Some(Status::Committed) => status.commit_ts,
+                Some(Status::RolledBack) => 0,
+                // Wait at least a millisecond, the TTL is rounded.
+                _ => return Box::new(future::ok(status.lock_ttl.max(1))),
+            };
+            let mut keys = status.secondaries;
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+            let req = ResolveLockRequest {
+                start_ts: lock_ts,
+                commit_ts,
+                keys,
+            };
+            Box::new(txn_client.resolve_lock(&req).map(|_| 0))
+        }))
+    }
+}
+
+// AsyncClient starts transactions whose requests all return futures. It
+// holds no transaction state, so it can be cloned and shared across threads.
+#[derive(Clone)]
+pub struct AsyncClient {
+    txn_client: TransactionClient,
+    resolver: LockResolver,
+    timestamps: TimestampBatcher,
+    config: ClientConfig,
+}
+
+// The number of pairs fetched by one scan request.
+const SCAN_BATCH_SIZE: usize = 64;
+// The TTL in milliseconds of the locks written by the client.
+const LOCK_TTL_MS: u64 = 100;
+// The interval between heartbeats, well within the lock TTL.
+const HEARTBEAT_INTERVAL_MS: u64 = 40;
+// The most timestamps fetched by one request.
+const MAX_TIMESTAMP_BATCH: usize = 1024;
+
+fn sleep(ms: u64) -> RpcFuture<()> {
+    Box::new(Delay::new(Duration::from_millis(ms)).map_err(|e| Error::Other(e.to_string())))
+}
+
+// Backoff spaces out the attempts of one operation under a retry policy.
+#[derive(Clone)]
+struct Backoff {
+    policy: RetryPolicy,
+    attempts: usize,
+    start: Instant,
+}
+
+impl Backoff {
+    fn new(policy: RetryPolicy) -> Backoff {
+        Backoff {
+            policy,
+            attempts: 0,
+            start: Instant::now(),
+        }
+    }
+
+    // Records a failed attempt and returns the milliseconds to wait before
+    // the next one, or None if the attempts or the deadline are spent.
+    fn next_delay(&mut self) -> Option<u64> {
+        self.attempts += 1;
+        if self.attempts >= self.policy.max_attempts {
+            return None;
+        }
+        let delay = self.policy.delay_ms(self.attempts - 1);
+        match self.policy.deadline_ms {
+            Some(deadline)
+                if self.start.elapsed() + Duration::from_millis(delay)
+                    > Duration::from_millis(deadline) =>
+            {
+                None
+            }
+            _ => Some(delay),
+        }
+    }
+}
+
+// Continues with `state` after `delay` milliseconds, or gives up without a
+// delay.
+fn retry_after<T, S>(delay: Option<u64>, state: S) -> RpcFuture<Loop<T, S>>
+where
+    T: Send + 'static,
+    S: Send + 'static,
+{
+    match delay {
+        Some(delay) => Box::new(sleep(delay).map(move |_| Loop::Continue(state))),
+        None => Box::new(future::err(Error::Timeout)),
+    }
+}
+
+// TimestampBatcher merges the timestamps requested concurrently by the
+// transactions of a client into one request for a range of timestamps. A
+// request waiting while another one is in flight goes out with the next
+// batch, so its timestamp is always fetched after it was asked for. The
+// batches go to the replica that answered the last one, and move on to
+// another replica when it is not the leader of the oracle.
+#[derive(Clone)]
+struct TimestampBatcher {
+    waiters: Arc<Mutex<Sender<oneshot::Sender<Result<TimeStamp>>>>>,
+}
+
+impl TimestampBatcher {
+    // Starts the thread sending the batches, it stops once every clone of
+    // the batcher is dropped.
+    fn new(tso_clients: Vec<TSOClient>, policy: RetryPolicy) -> TimestampBatcher {
+        assert!(!tso_clients.is_empty());
+        let (tx, rx) = mpsc::channel::<oneshot::Sender<Result<TimeStamp>>>();
+        thread::spawn(move || {
+            let mut leader = 0;
+            while let Ok(waiter) = rx.recv() {
+                let mut waiters = vec![waiter];
+                waiters.extend(rx.try_iter().take(MAX_TIMESTAMP_BATCH - 1));
+                let count = waiters.len() as u32;
+                match fetch_timestamps(&tso_clients, leader, policy, count).wait() {
+                    Ok((ts, replica)) => {
+                        leader = replica;
+                        for (i, waiter) in waiters.into_iter().enumerate() {
+                            let _ = waiter.send(Ok(ts + i as u64));
+                        }
+                    }
+                    Err(e) => {
+                        for waiter in waiters {
+                            let _ = waiter.send(Err(e.clone()));
+                        }
+                    }
+                }
+            }
+        });
+        TimestampBatcher {
+            waiters: Arc::new(Mutex::new(tx)),
+        }
+    }
+
+    fn get_timestamp(&self) -> RpcFuture<TimeStamp> {
+        let (tx, rx) = oneshot::channel();
+        if self.waiters.lock().unwrap().send(tx).is_err() {
+            return Box::new(future::err(Error::Stopped));
+        }
+        Box::new(rx.then(|res| res.unwrap_or_else(|e| Err(Error::Recv(e)))))
+    }
+}
+
+// Fetches `count` contiguous timestamps from the leader of the replicas,
+// trying the one at `leader` first. Resolves to the first timestamp and the
+// replica that handed it out.
+fn fetch_timestamps(
+    tso_clients: &[TSOClient],
+    leader: usize,
+    policy: RetryPolicy,
+    count: u32,
+) -> RpcFuture<(TimeStamp, usize)> {
+    let tso_clients = tso_clients.to_vec();
+    let backoff = Backoff::new(policy);
+    Box::new(future::loop_fn(
+        (backoff, leader),
+        move |(mut backoff, leader)| {
+            let replicas = tso_clients.len();
+            tso_clients[leader]
+                .get_timestamp(&TimestampRequest { count })
+                .then(
+                    move |res| -> RpcFuture<Loop<(TimeStamp, usize), (Backoff, usize)>> {
+                        match res {
+                            Ok(TimestampResponse {
+                                ts,
+                                not_leader: None,
+                            }) => Box::new(future::ok(Loop::Break((ts.into(), leader)))),
+                            // Follow the leader known to the replica right away.
+                            Ok(TimestampResponse {
+                                not_leader:
+                                    Some(NotLeader {
+                                        has_leader: true,
+                                        leader: hint,
+                                    }),
+                                ..
+                            }) if (hint as usize) < replicas => {
+                                let delay = backoff.next_delay().map(|_| 0);
+                                retry_after(delay, (backoff, hint as usize))
+                            }
+                            _ => retry_after(
+                                backoff.next_delay(),
+                                (backoff, (leader + 1) % replicas),
+                            ),
+                        }
+                    },
+                )
+        },
+    ))
+}
+
+// Heartbeat keeps the primary lock of a transaction alive in the background
+// while it is being committed. It stops when dropped.
+struct Heartbeat {
+    _stop: oneshot::Sender<()>,
+}
+
+impl AsyncClient {
+    pub fn new(tso_client: TSOClient, txn_client: TransactionClient) -> AsyncClient {
+        AsyncClient::with_config(tso_client, txn_client, ClientConfig::default())
+    }
+
+    pub fn with_config(
+        tso_client: TSOClient,
+        txn_client: TransactionClient,
+        config: ClientConfig,
+    ) -> AsyncClient {
+        AsyncClient::with_tso_replicas(vec![tso_client], txn_client, config)
+    }
+
+    // Takes a client for every replica of a replicated timestamp oracle, the
+    // timestamps are fetched from whichever is the leader.
+    pub fn with_tso_replicas(
+        tso_clients: Vec<TSOClient>,
+        txn_client: TransactionClient,
+        config: ClientConfig,
+    ) -> AsyncClient {
+        AsyncClient {
+            timestamps: TimestampBatcher::new(tso_clients, config.tso_retry),
+            resolver: LockResolver::new(txn_client.clone()),
+            txn_client,
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &ClientConfig {
+        &self.config
+    }
+
+    pub fn set_batch_size(&mut self, batch_size: usize) {
+        assert!(batch_size > 0);
+        self.config.batch_size = batch_size;
+    }
+
+    // Timestamps requested at the same time share one request.
+    pub fn get_timestamp(&self) -> RpcFuture<TimeStamp> {
+        self.timestamps.get_timestamp()
+    }
+
+    pub fn begin(&self) -> RpcFuture<AsyncTransaction> {
+        Box::new(self.snapshot().map(|snapshot| AsyncTransaction {
+            snapshot,
+            writes: BTreeMap::new(),
+        }))
+    }
+
+    // Starts a read-only transaction.
+    pub fn snapshot(&self) -> RpcFuture<AsyncSnapshot> {
+        let client = self.clone();
+        Box::new(
+            self.get_timestamp()
+                .map(move |start_ts| AsyncSnapshot { client, start_ts }),
+        )
+    }
+
+    fn get(&self, start_ts: TimeStamp, key: Vec<u8>) -> RpcFuture<Option<Vec<u8>>> {
+        let txn_client = self.txn_client.clone();
+        let req = GetRequest {
+            start_ts: start_ts.into(),
+            key,
+        };
+        self.read(start_ts, move || {
+            Box::new(txn_client.get(&req).map(|res| {
+                let value = if res.not_found { None } else { Some(res.value) };
+                (res.error, value)
+            }))
+        })
+    }
+
+    fn scan(
+        &self,
+        start_ts: TimeStamp,
+        start_key: Vec<u8>,
+        end_key: Vec<u8>,
+        limit: usize,
+        reverse: bool,
+    ) -> RpcFuture<Vec<(Vec<u8>, Vec<u8>)>> {
+        let client = self.clone();
+        Box::new(future::loop_fn(
+            (start_key, end_key, vec![]),
+            move |(mut start_key, mut end_key, mut pairs): (_, _, Vec<(Vec<u8>, Vec<u8>)>)| {
+                let batch_size = if limit == 0 {
+                    SCAN_BATCH_SIZE
+                } else {
+                    SCAN_BATCH_SIZE.min(limit - pairs.len())
+                };
+                let batch = client.scan_batch(ScanRequest {
+                    start_ts: start_ts.into(),
+                    start_key: start_key.clone(),
+                    end_key: end_key.clone(),
+                    limit: batch_size as u32,
+                    reverse,
+                });
+                batch.map(move |batch| {
+                    let mut done = batch.len() < batch_size;
+                    if let Some(last) = batch.last() {
+                        if reverse {
+                            // Nothing sorts below the empty key, which as an
+                            // end key would mean unbounded.
+                            done |= last.key.is_empty();
+                            end_key = last.key.clone();
+                        } else {
+                            start_key = last.key.clone();
+                            start_key.push(0);
+                        }
+                    }
+                    pairs.extend(batch.into_iter().map(|p| (p.key, p.value)));
+                    if done || (limit != 0 && pairs.len() == limit) {
+                        Loop::Break(pairs)
+                    } else {
+                        Loop::Continue((start_key, end_key, pairs))
+                    }
+                })
+            },
+        ))
+    }
+
+    fn scan_batch(&self, req: ScanRequest) -> RpcFuture<Vec<KvPair>> {
+        let txn_client = self.txn_client.clone();
+        self.read(req.start_ts.into(), move || {
+            Box::new(txn_client.scan(&req).map(|res| (res.error, res.pairs)))
+        })
+    }
+
+    // Sends a read of the snapshot at `start_ts` until it gets an answer,
+    // resolving the locks in its way.
+    fn read<T, F>(&self, start_ts: TimeStamp, send: F) -> RpcFuture<T>
+    where
+        T: Send + 'static,
+        F: Fn() -> RpcFuture<(Option<KeyError>, T)> + Send + 'static,
+    {
+        let client = self.clone();
+        let backoffs = (
+            Backoff::new(self.config.network_retry),
+            Backoff::new(self.config.lock_retry),
+        );
+        Box::new(future::loop_fn(backoffs, move |(mut network, mut lock)| {
+            let client = client.clone();
+            send().then(move |res| -> RpcFuture<Loop<T, (Backoff, Backoff)>> {
+                let e = match res {
+                    Ok((None, value)) => return Box::new(future::ok(Loop::Break(value))),
+                    Ok((Some(e), _)) => e,
+                    Err(_) => return retry_after(network.next_delay(), (network, lock)),
+                };
+                match client.resolve_key_error(e, start_ts) {
+                    // Retry as soon as the lock is resolved or has expired.
+                    Some(wait) => Box::new(wait.and_then(move |wait| {
+                        let delay = lock.next_delay().map(|delay| delay.min(wait));
+                        retry_after(delay, (network, lock))
+                    })),
+                    None => retry_after(network.next_delay(), (network, lock)),
+                }
+            })
+        }))
+    }
+
+    // Tries to resolve the lock that blocks the transaction at `start_ts`,
+    // resolving to the milliseconds to wait before retrying, zero if the
+    // lock is gone. Returns None for the errors that are not a lock.
+    fn resolve_key_error(&self, e: KeyError, start_ts: TimeStamp) -> Option<RpcFuture<u64>> {
+        let lock = match e.error {
+            Some(key_error::Error::Locked(lock)) => lock,
+            _ => return None,
+        };
+        let resolver = self.resolver.clone();
+        let lock_ttl = lock.lock_ttl;
+        // Without an answer from the primary, wait for the whole TTL.
+        Some(Box::new(
+            self.get_timestamp()
+                .and_then(move |current_ts| resolver.resolve(&lock, start_ts, current_ts))
+                .or_else(move |_| Ok(lock_ttl)),
+        ))
+    }
+
+    fn rollback_keys(&self, start_ts: TimeStamp, keys: Vec<Vec<u8>>) -> RpcFuture<()> {
+        if keys.is_empty() {
+            return Box::new(future::ok(()));
+        }
+        let txn_client = self.txn_client.clone();
+        let req = RollbackRequest {
+            start_ts: start_ts.into(),
+            keys,
+        };
+        let backoff = Backoff::new(self.config.network_retry);
+        Box::new(future::loop_fn(backoff, move |mut backoff| {
+            txn_client
+                .rollback(&req)
+                .then(move |res| -> RpcFuture<Loop<(), Backoff>> {
+                    match res {
+                        Ok(res) => match res.error {
+                            None => Box::new(future::ok(Loop::Break(()))),
+                            Some(e) => Box::new(future::err(Error::Other(format!("{:?}", e)))),
+                        },
+                        Err(_) => retry_after(backoff.next_delay(), backoff),
+                    }
+                })
+        }))
+    }
+
+    fn prewrite_request(
+        &self,
+        start_ts: TimeStamp,
+        writes: &[Write],
+        batch: &[Write],
+        lock_ttl: u64,
+    ) -> PrewriteRequest {
+        let primary = &writes[0];
+        // The primary lock keeps track of the rest of the transaction.
+        let secondaries = if batch.iter().any(|w| w.0 == primary.0) {
+            writes[1..].iter().map(|w| w.0.clone()).collect()
+        } else {
+            vec![]
+        };
+        PrewriteRequest {
+            start_ts: start_ts.into(),
+            mutations: batch.iter().map(|w| w.to_msg()).collect(),
+            primary: primary.0.clone(),
+            lock_ttl,
+            txn_size: writes.len() as u64,
+            secondaries,
+        }
+    }
+
+    // Sends a prewrite, resolving the stale locks in its way. Resolves to
+    // false if the prewrite conflicts with another transaction.
+    fn prewrite(&self, req: PrewriteRequest) -> RpcFuture<bool> {
+        let client = self.clone();
+        let backoff = Backoff::new(self.config.lock_retry);
+        Box::new(future::loop_fn(backoff, move |mut backoff| {
+            let client = client.clone();
+            let start_ts = TimeStamp::from(req.start_ts);
+            client
+                .txn_client
+                .prewrite(&req)
+                .then(move |res| -> RpcFuture<Loop<bool, Backoff>> {
+                    let e = match res {
+                        Ok(PrewriteResponse { error: None }) => {
+                            return Box::new(future::ok(Loop::Break(true)));
+                        }
+                        Ok(PrewriteResponse { error: Some(e) }) => e,
+                        Err(_) => return Box::new(future::ok(Loop::Break(false))),
+                    };
+                    match client.resolve_key_error(e, start_ts) {
+                        // A live lock is a conflict, only a stale one is resolved.
+                        Some(wait) => Box::new(wait.map(move |wait| {
+                            if wait == 0 && backoff.next_delay().is_some() {
+                                Loop::Continue(backoff)
+                            } else {
+                                Loop::Break(false)
+                            }
+                        })),
+                        None => Box::new(future::ok(Loop::Break(false))),
+                    }
+                })
+        }))
+    }
+
+    // Sends the prewrites at once and waits for all of them. A prewrite
+    // that hits a lock is retried on its own.
+    fn prewrite_parallel(&self, reqs: Vec<PrewriteRequest>) -> RpcFuture<bool> {
+        let client = self.clone();
+        let resps: Vec<_> = reqs
+            .iter()
+            .map(|req| self.txn_client.prewrite(req).then(Ok::<_, Error>))
+            .collect();
+        let resps = future::join_all(resps);
+        Box::new(resps.and_then(move |resps| -> RpcFuture<bool> {
+            let mut retries = vec![];
+            for (req, resp) in reqs.into_iter().zip(resps) {
+                match resp {
+                    Ok(PrewriteResponse { error: None }) => {}
+                    Ok(PrewriteResponse {
+                        error:
+                            Some(KeyError {
+                                error: Some(key_error::Error::Locked(_)),
+                            }),
+                    }) => retries.push(client.prewrite(req)),
+                    _ => return Box::new(future::ok(false)),
+                }
+            }
+            Box::new(future::join_all(retries).map(|oks| oks.into_iter().all(|ok| ok)))
+        }))
+    }
+
+    // Prewrites every key of the transaction. The first batch holds the
+    // primary, so that it is locked before any of the secondaries. Resolves
+    // to the heartbeat of the primary lock, which the commit keeps until the
+    // primary is committed, or None if a prewrite failed. Like the
+    // heartbeats, the locks live for LOCK_TTL_MS past the current time,
+    // however long the transaction has been open.
+    fn prewrite_txn(&self, start_ts: TimeStamp, writes: &[Write]) -> RpcFuture<Option<Heartbeat>> {
+        let client = self.clone();
+        let writes = writes.to_vec();
+        Box::new(
+            self.get_timestamp()
+                .and_then(move |current_ts| -> RpcFuture<Option<Heartbeat>> {
+                    let lock_ttl = current_ts.elapsed_ms_since(start_ts) + LOCK_TTL_MS;
+                    let mut reqs: Vec<PrewriteRequest> = writes
+                        .chunks(client.config.batch_size)
+                        .map(|batch| client.prewrite_request(start_ts, &writes, batch, lock_ttl))
+                        .collect();
+                    let rest = reqs.split_off(1);
+                    let primary = writes[0].0.clone();
+                    Box::new(client.prewrite(reqs.remove(0)).and_then(
+                        move |ok| -> RpcFuture<Option<Heartbeat>> {
+                            if !ok {
+                                return Box::new(future::ok(None));
+                            }
+                            let heartbeat = client.start_heartbeat(start_ts, primary);
+                            Box::new(client.prewrite_parallel(rest).map(move |ok| {
+                                if ok {
+                                    Some(heartbeat)
+                                } else {
+                                    None
+                                }
+                            }))
+                        },
+                    ))
+                }),
+        )
+    }
+
+    // Extends the TTL of the primary lock every heartbeat interval, so that
+    // it stays alive for LOCK_TTL_MS past the latest heartbeat.
+    fn start_heartbeat(&self, start_ts: TimeStamp, primary_key: Vec<u8>) -> Heartbeat {
+        let (stop, stopped) = oneshot::channel();
+        let timestamps = self.timestamps.clone();
+        let txn_client = self.txn_client.clone();
+        let beats = future::loop_fn((), move |()| {
+            let txn_client = txn_client.clone();
+            let primary_key = primary_key.clone();
+            let timestamps = timestamps.clone();
+            sleep(HEARTBEAT_INTERVAL_MS)
+                .and_then(move |()| timestamps.get_timestamp())
+                .and_then(move |current_ts| {
+                    let elapsed = current_ts.elapsed_ms_since(start_ts);
+                    txn_client.txn_heartbeat(&TxnHeartBeatRequest {
+                        primary_key,
+                        start_ts: start_ts.into(),
+                        advise_lock_ttl: elapsed + LOCK_TTL_MS,
+                    })
+                })
+                .then(|res| match res {
+                    // The transaction is no longer locked.
+                    Ok(TxnHeartBeatResponse { error: Some(_), .. }) => Ok(Loop::Break(())),
+                    _ => Ok(Loop::Continue(())),
+                })
+        });
+        let stopped = stopped.then(|_| Ok::<_, Error>(()));
+        self.txn_client
+            .spawn(beats.select(stopped).map(|_| ()).map_err(|_| ()));
+        Heartbeat { _stop: stop }
+    }
+
+    // Resolves to the state of the transaction, or None if it cannot be
+    // determined. The primary lock has not expired as of the start
+    // timestamp, but a primary with neither a lock nor a commit record is
+    // rolled back, so that a commit still in flight cannot land.
+    fn primary_status(
+        &self,
+        start_ts: TimeStamp,
+        primary_key: Vec<u8>,
+    ) -> RpcFuture<Option<Status>> {
+        let status = self.txn_client.check_txn_status(&CheckTxnStatusRequest {
+            primary_key,
+            lock_ts: start_ts.into(),
+            caller_start_ts: start_ts.into(),
+            current_ts: start_ts.into(),
+        });
+        Box::new(status.then(|res| Ok(res.ok().and_then(|res| Status::from_i32(res.status)))))
+    }
+
+    // Commits the primary, which decides the outcome of the transaction.
+    // Resolves to the commit timestamp, or None if the transaction has been
+    // rolled back. A commit timestamp below the smallest one the primary
+    // lock allows is replaced by a newer one.
+    fn commit_primary(
+        &self,
+        start_ts: TimeStamp,
+        keys: Vec<Vec<u8>>,
+    ) -> RpcFuture<Option<TimeStamp>> {
+        let client = self.clone();
+        Box::new(self.get_timestamp().and_then(move |commit_ts| {
+            let req = CommitRequest {
+                is_primary: true,
+                start_ts: start_ts.into(),
+                commit_ts: commit_ts.into(),
+                keys: vec![keys[0].clone()],
+            };
+            client
+                .txn_client
+                .commit(&req)
+                .then(move |res| -> RpcFuture<Option<TimeStamp>> {
+                    match res {
+                        Ok(CommitResponse { error: None }) => Box::new(future::ok(Some(commit_ts))),
+                        Ok(CommitResponse {
+                            error:
+                                Some(KeyError {
+                                    error: Some(key_error::Error::CommitTsExpired(_)),
+                                }),
+                        }) => client.commit_primary(start_ts, keys),
+                        // The transaction has been rolled back by someone else.
+                        Ok(CommitResponse { error: Some(_) }) => Box::new(future::ok(None)),
+                        Err(e) => {
+                            // The commit may or may not have been applied. If the
+                            // primary is still locked it was not, and rolling back
+                            // settles it.
+                            let status = client.primary_status(start_ts, keys[0].clone());
+                            Box::new(status.and_then(
+                                move |status| -> RpcFuture<Option<TimeStamp>> {
+                                    match status {
+                                        Some(Status::Locked) | Some(Status::RolledBack) => {
+                                            Box::new(client.rollback_keys(start_ts, keys).then(
+                                                |res| match res {
+                                                    Ok(()) => Ok(None),
+                                                    Err(_) => Err(e),
+                                                },
+                                            ))
+                                        }
+                                        _ => Box::new(future::err(e)),
+                                    }
+                                },
+                            ))
+                        }
+                    }
+                })
+        }))
+    }
+
+    // Second phase: write out write records for secondary cells.
+    // The transaction is committed once the primary is, so this is done
+    // in the background. The secondaries that fail to commit keep their
+    // locks, which are rolled forward by lock resolution.
+    fn commit_secondaries(
+        &self,
+        start_ts: TimeStamp,
+        commit_ts: TimeStamp,
+        secondaries: &[Vec<u8>],
+    ) {
+        let reqs: Vec<CommitRequest> = secondaries
+            .chunks(self.config.batch_size)
+            .map(|batch| CommitRequest {
+                is_primary: false,
+                start_ts: start_ts.into(),
+                commit_ts: commit_ts.into(),
+                keys: batch.to_vec(),
+            })
+            .collect();
+        if reqs.is_empty() {
+            return;
+        }
+        let commits: Vec<_> = reqs
+            .iter()
+            .map(|req| self.txn_client.commit(req).then(Ok::<_, ()>))
+            .collect();
+        self.txn_client.spawn(future::join_all(commits).map(|_| ()));
+    }
+
+    fn commit(&self, start_ts: TimeStamp, writes: Vec<Write>) -> RpcFuture<bool> {
+        // A transaction without writes has nothing to commit.
+        if writes.is_empty() {
+            return Box::new(future::ok(true));
+        }
+        let keys: Vec<Vec<u8>> = writes.iter().map(|w| w.0.clone()).collect();
+        let client = self.clone();
+        Box::new(self.prewrite_txn(start_ts, &writes).and_then(
+            move |heartbeat| -> RpcFuture<bool> {
+                let heartbeat = match heartbeat {
+                    Some(heartbeat) => heartbeat,
+                    None => {
+                        return Box::new(client.rollback_keys(start_ts, keys).then(|_| Ok(false)));
+                    }
+                };
+                let committed = client.commit_primary(start_ts, keys.clone());
+                Box::new(committed.map(move |commit_ts| {
+                    drop(heartbeat);
+                    match commit_ts {
+                        Some(commit_ts) => {
+                            client.commit_secondaries(start_ts, commit_ts, &keys[1..]);
+                            true
+                        }
+                        None => false,
+                    }
+                }))
+            },
+        ))
+    }
+}
+
+// AsyncSnapshot reads the data committed before its start timestamp.
+pub struct AsyncSnapshot {
+    client: AsyncClient,
+    start_ts: TimeStamp,
+}
+
+impl AsyncSnapshot {
+    pub fn start_ts(&self) -> TimeStamp {
+        self.start_ts
+    }
+
+    // Resolves to None if the key has no value.
+    pub fn get(&self, key: Vec<u8>) -> RpcFuture<Option<Vec<u8>>> {
+        self.client.get(self.start_ts, key)
+    }
+
+    // Range, order and limit are as in Transaction::scan in client.rs.
+    pub fn scan(
+        &self,
+        start_key: Vec<u8>,
+        end_key: Vec<u8>,
+        limit: usize,
+        reverse: bool,
+    ) -> RpcFuture<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.client
+            .scan(self.start_ts, start_key, end_key, limit, reverse)
+    }
+}
+
+// AsyncTransaction buffers the writes of a transaction until it is
+// committed. Reads see its snapshot overlaid with the buffered writes.
+pub struct AsyncTransaction {
+    snapshot: AsyncSnapshot,
+    // The latest write of every key, in key order.
+    writes: BTreeMap<Vec<u8>, (Op, Vec<u8>)>,
+}
+
+impl AsyncTransaction {
+    pub fn start_ts(&self) -> TimeStamp {
+        self.snapshot.start_ts
+    }
+
+    pub fn get(&self, key: Vec<u8>) -> RpcFuture<Option<Vec<u8>>> {
+        match self.writes.get(&key) {
+            Some((Op::Put, value)) => Box::new(future::ok(Some(value.clone()))),
+            Some((Op::Delete, _)) => Box::new(future::ok(None)),
+            _ => self.snapshot.get(key),
+        }
+    }
+
+    // Scans the snapshot overlaid with the buffered writes, see Transaction::scan.
+    pub fn scan(
+        &self,
+        start_key: Vec<u8>,
+        end_key: Vec<u8>,
+        limit: usize,
+        reverse: bool,
+    ) -> RpcFuture<Vec<(Vec<u8>, Vec<u8>)>> {
+        let pending: Vec<(Vec<u8>, Option<Vec<u8>>)> = if end_key.is_empty() || start_key < end_key
+        {
+            let upper = if end_key.is_empty() {
+                Bound::Unbounded
+            } else {
+                Bound::Excluded(end_key.clone())
+            };
+            self.writes
+                .range((Bound::Included(start_key.clone()), upper))
+                .filter_map(|(key, (op, value))| match op {
+                    Op::Put => Some((key.clone(), Some(value.clone()))),
+                    Op::Delete => Some((key.clone(), None)),
+                    Op::Lock => None,
+                })
+                .collect()
+        } else {
+            vec![]
+        };
+        // Every deleted key may hide one of the pairs read from the
+        // snapshot, so read that many more.
+        let deletes = pending.iter().filter(|(_, value)| value.is_none()).count();
+        let snapshot_limit = if limit == 0 { 0 } else { limit + deletes };
+        let pairs = self
+            .snapshot
+            .scan(start_key, end_key, snapshot_limit, reverse);
+        Box::new(pairs.map(move |pairs| {
+            let mut merged: BTreeMap<_, _> = pairs.into_iter().collect();
+            for (key, value) in pending {
+                match value {
+                    Some(value) => merged.insert(key, value),
+                    None => merged.remove(&key),
+                };
+            }
+            let mut pairs: Vec<_> = if reverse {
+                merged.into_iter().rev().collect()
+            } else {
+                merged.into_iter().collect()
+            };
+            if limit != 0 {
+                pairs.truncate(limit);
+            }
+            pairs
+        }))
+    }
+
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.insert(key, (Op::Put, value));
+    }
+
+    pub fn delete(&mut self, key: Vec<u8>) {
+        self.writes.insert(key, (Op::Delete, vec![]));
+    }
+
+    pub fn commit(self) -> RpcFuture<bool> {
+        let writes = self
+            .writes
+            .into_iter()
+            .map(|(key, (op, value))| Write(key, value, op))
+            .collect();
+        let AsyncSnapshot { client, start_ts } = self.snapshot;
+        client.commit(start_ts, writes)
+    }
+
+    // Rolls back every key of the transaction, so that none of its
+    // prewrites can succeed afterwards.
+    pub fn rollback(self) -> RpcFuture<()> {
+        let keys = self.writes.into_keys().collect();
+        let AsyncSnapshot { client, start_ts } = self.snapshot;
+        client.rollback_keys(start_ts, keys)
+    }
+}
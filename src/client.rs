@@ -1,186 +1,139 @@
-use crate::msg::{CommitRequest, GetRequest, PrewriteRequest, TimestampRequest};
+use crate::async_client::{AsyncClient, AsyncSnapshot, AsyncTransaction};
+use crate::config::ClientConfig;
 use crate::service::{TSOClient, TransactionClient};
-use crate::Write;
-
-use std::time::Duration;
+use crate::TimeStamp;
 
 use futures::Future;
-use futures_timer::Delay;
 use labrpc::*;
 
-#[derive(Clone, Default)]
-struct Transaction {
-    start_ts: u64,
-    writes: Vec<Write>,
-}
-
+// Client is the blocking interface of AsyncClient, every call waits for its
+// requests to finish.
 #[derive(Clone)]
 pub struct Client {
-    tso_client: TSOClient,
-    txn_client: TransactionClient,
-    txn: Transaction,
+    inner: AsyncClient,
 }
 
-const BACKOFF_TIME_MS: u64 = 100;
-const RETRY_TIMES: usize = 3;
-
 impl Client {
     pub fn new(tso_client: TSOClient, txn_client: TransactionClient) -> Client {
         Client {
-            tso_client,
-            txn_client,
-            txn: Transaction {
-                ..Default::default()
-            },
+            inner: AsyncClient::new(tso_client, txn_client),
         }
     }
 
-    pub fn get_timestamp(&self) -> Result<u64> {
-        let mut backoff = BACKOFF_TIME_MS;
-        for _i in 0..RETRY_TIMES {
-            match self.tso_client.get_timestamp(&TimestampRequest {}).wait() {
-                Ok(res) => {
-                    return Ok(res.ts);
-                }
-                Err(_) => {
-                    Delay::new(Duration::from_millis(backoff)).wait().unwrap();
-                    backoff *= 2;
-                    continue;
-                }
-            }
-        }
-        Err(Error::Timeout)
-    }
-
-    pub fn begin(&mut self) {
-        let start_ts = match self.get_timestamp() {
-            Ok(ts) => ts,
-            Err(Error::Timeout) => {
-                println!("get timestamp timeout");
-                return;
-            }
-            _ => panic!("unexpected behavior"),
-        };
-        self.txn = Transaction {
-            start_ts,
-            writes: vec![],
-        };
-    }
-
-    pub fn get(&self, key: Vec<u8>) -> Result<Vec<u8>> {
-        let mut backoff = BACKOFF_TIME_MS;
-        for _i in 0..RETRY_TIMES {
-            match self
-                .txn_client
-                .get(&GetRequest {
-                    start_ts: self.txn.start_ts,
-                    key: key.clone(),
-                })
-                .wait()
-            {
-                Ok(res) => {
-                    return Ok(res.value);
-                }
-                Err(_) => {
-                    Delay::new(Duration::from_millis(backoff)).wait().unwrap();
-                    backoff *= 2;
-                    continue;
-                }
-            }
+    pub fn with_config(
+        tso_client: TSOClient,
+        txn_client: TransactionClient,
+        config: ClientConfig,
+    ) -> Client {
+        Client {
+            inner: AsyncClient::with_config(tso_client, txn_client, config),
         }
-        Err(Error::Timeout)
     }
 
-    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
-        self.txn.writes.push(Write(key, value));
-    }
-
-    pub fn commit(&self) -> Result<bool> {
-        let primary = &self.txn.writes[0];
-        let secondaries = &self.txn.writes[1..];
-
-        if self
-            .txn_client
-            .prewrite(&PrewriteRequest {
-                start_ts: self.txn.start_ts,
-                write: Some(crate::msg::Write {
-                    key: primary.0.clone(),
-                    value: primary.1.clone(),
-                }),
-                primary: Some(crate::msg::Write {
-                    key: primary.0.clone(),
-                    value: primary.1.clone(),
-                }),
-            })
-            .wait()
-            .is_err()
-        {
-            return Ok(false);
+    // Takes a client for every replica of a replicated timestamp oracle.
+    pub fn with_tso_replicas(
+        tso_clients: Vec<TSOClient>,
+        txn_client: TransactionClient,
+        config: ClientConfig,
+    ) -> Client {
+        Client {
+            inner: AsyncClient::with_tso_replicas(tso_clients, txn_client, config),
         }
+    }
 
-        for w in secondaries {
-            if self
-                .txn_client
-                .prewrite(&PrewriteRequest {
-                    start_ts: self.txn.start_ts,
-                    write: Some(crate::msg::Write {
-                        key: w.0.clone(),
-                        value: w.1.clone(),
-                    }),
-                    primary: Some(crate::msg::Write {
-                        key: primary.0.clone(),
-                        value: primary.1.clone(),
-                    }),
-                })
-                .wait()
-                .is_err()
-            {
-                return Ok(false);
-            }
-        }
+    pub fn config(&self) -> &ClientConfig {
+        self.inner.config()
+    }
 
-        let commit_ts = self.get_timestamp()?;
-        // Commit primary first.
-        match self
-            .txn_client
-            .commit(&CommitRequest {
-                is_primary: true,
-                start_ts: self.txn.start_ts,
-                commit_ts,
-                write: Some(crate::msg::Write {
-                    key: primary.0.clone(),
-                    value: primary.1.clone(),
-                }),
-            })
-            .wait()
-        {
-            Ok(_) => {}
-            Err(Error::Other(e)) => {
-                if e == "resphook" {
-                    return Err(Error::Other("resphook".to_owned()));
-                } else {
-                    return Ok(false);
-                }
-            }
-            Err(_) => return Ok(false),
-        }
+    pub fn set_batch_size(&mut self, batch_size: usize) {
+        self.inner.set_batch_size(batch_size);
+    }
 
-        // Second phase: write out write records for secondary cells.
-        for w in secondaries {
-            let _ = self
-                .txn_client
-                .commit(&CommitRequest {
-                    is_primary: false,
-                    start_ts: self.txn.start_ts,
-                    commit_ts,
-                    write: Some(crate::msg::Write {
-                        key: w.0.clone(),
-                        value: w.1.clone(),
-                    }),
-                })
-                .wait();
-        }
+    pub fn get_timestamp(&self) -> Result<TimeStamp> {
+        self.inner.get_timestamp().wait()
+    }
+
+    pub fn begin(&self) -> Result<Transaction> {
+        let inner = self.inner.begin().wait()?;
+        Ok(Transaction { inner })
+    }
+
+    // Starts a read-only transaction.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        let inner = self.inner.snapshot().wait()?;
+        Ok(Snapshot { inner })
+    }
+}
+
+// Snapshot is the blocking interface of AsyncSnapshot.
+pub struct Snapshot {
+    inner: AsyncSnapshot,
+}
+
+impl Snapshot {
+    pub fn start_ts(&self) -> TimeStamp {
+        self.inner.start_ts()
+    }
+
+    pub fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.inner.get(key).wait()
+    }
+
+    // Scans the snapshot the way Transaction::scan does.
+    pub fn scan(
+        &self,
+        start_key: Vec<u8>,
+        end_key: Vec<u8>,
+        limit: usize,
+        reverse: bool,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.inner.scan(start_key, end_key, limit, reverse).wait()
+    }
+}
+
+// Transaction is the blocking interface of AsyncTransaction. It is consumed
+// by commit or rollback.
+pub struct Transaction {
+    inner: AsyncTransaction,
+}
+
+impl Transaction {
+    pub fn start_ts(&self) -> TimeStamp {
+        self.inner.start_ts()
+    }
+
+    pub fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.inner.get(key).wait()
+    }
+
+    // Returns the pairs in [start_key, end_key) in key order, or in reverse
+    // order if `reverse` is set. An empty end_key is unbounded and a zero
+    // limit returns every pair in the range.
+    pub fn scan(
+        &self,
+        start_key: Vec<u8>,
+        end_key: Vec<u8>,
+        limit: usize,
+        reverse: bool,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.inner.scan(start_key, end_key, limit, reverse).wait()
+    }
+
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.inner.set(key, value);
+    }
+
+    pub fn delete(&mut self, key: Vec<u8>) {
+        self.inner.delete(key);
+    }
+
+    pub fn commit(self) -> Result<bool> {
+        self.inner.commit().wait()
+    }
 
-        Ok(true)
+    // Rolls back every key of the transaction, so that none of its
+    // prewrites can succeed afterwards.
+    pub fn rollback(self) -> Result<()> {
+        self.inner.rollback().wait()
     }
 }
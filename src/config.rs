@@ -0,0 +1,110 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+// RetryPolicy controls how the requests failing with one class of error are
+// retried. The delay doubles on every retry, starting from the base delay
+// and bounded by the max delay.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    // The number of attempts before giving up, including the first one.
+    pub max_attempts: usize,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+    // The fraction of every delay that is randomized, between 0 and 1.
+    pub jitter: f64,
+    // The time an operation may spend before giving up, if any.
+    pub deadline_ms: Option<u64>,
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: usize, base_delay_ms: u64) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            base_delay_ms,
+            max_delay_ms: u64::MAX,
+            jitter: 0.0,
+            deadline_ms: None,
+        }
+    }
+
+    pub fn max_delay_ms(mut self, max_delay_ms: u64) -> RetryPolicy {
+        self.max_delay_ms = max_delay_ms;
+        self
+    }
+
+    pub fn jitter(mut self, jitter: f64) -> RetryPolicy {
+        assert!((0.0..=1.0).contains(&jitter));
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn deadline_ms(mut self, deadline_ms: u64) -> RetryPolicy {
+        self.deadline_ms = Some(deadline_ms);
+        self
+    }
+
+    // Returns the delay in milliseconds after the given failed attempt,
+    // counting from zero.
+    pub fn delay_ms(&self, attempt: usize) -> u64 {
+        let delay = self
+            .base_delay_ms
+            .checked_shl(attempt as u32)
+            .filter(|delay| delay >> attempt == self.base_delay_ms)
+            .unwrap_or(u64::MAX)
+            .min(self.max_delay_ms);
+        delay - (delay as f64 * self.jitter * random_fraction()) as u64
+    }
+}
+
+// Returns a random number in [0, 1).
+pub fn random_fraction() -> f64 {
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// ClientConfig is built by chaining its setters on the default config.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientConfig {
+    // The retries of the transaction requests failing in the network.
+    pub network_retry: RetryPolicy,
+    // The retries of the reads and prewrites blocked by a lock.
+    pub lock_retry: RetryPolicy,
+    // The retries of the timestamp requests.
+    pub tso_retry: RetryPolicy,
+    // The number of keys sent in one prewrite or commit request.
+    pub batch_size: usize,
+}
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig {
+            network_retry: RetryPolicy::new(3, 100),
+            lock_retry: RetryPolicy::new(3, 100),
+            tso_retry: RetryPolicy::new(3, 100),
+            batch_size: 16,
+        }
+    }
+}
+
+impl ClientConfig {
+    pub fn network_retry(mut self, policy: RetryPolicy) -> ClientConfig {
+        self.network_retry = policy;
+        self
+    }
+
+    pub fn lock_retry(mut self, policy: RetryPolicy) -> ClientConfig {
+        self.lock_retry = policy;
+        self
+    }
+
+    pub fn tso_retry(mut self, policy: RetryPolicy) -> ClientConfig {
+        self.tso_retry = policy;
+        self
+    }
+
+    pub fn batch_size(mut self, batch_size: usize) -> ClientConfig {
+        assert!(batch_size > 0);
+        self.batch_size = batch_size;
+        self
+    }
+}
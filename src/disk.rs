@@ -0,0 +1,370 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write as IoWrite};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+use crate::wal::{self, mutation, write_record};
+use crate::{Column, Engine, Key, KvTable, Lock, TimeStamp, Value, WriteKind};
+
+const WAL_FILE: &str = "wal";
+const CHECKPOINT_FILE: &str = "checkpoint";
+const CHECKPOINT_TMP_FILE: &str = "checkpoint.tmp";
+// Number of WAL records after which the table is checkpointed.
+const CHECKPOINT_INTERVAL: usize = 1024;
+// Every WAL record is prefixed with its length and its CRC-32.
+const FRAME_HEADER_LEN: usize = 8;
+
+// DiskEngine keeps the table in memory and makes it durable with an
+// append-only WAL of mutations plus periodic checkpoints of the whole table.
+pub struct DiskEngine {
+    table: KvTable,
+    dir: PathBuf,
+    wal: File,
+    pending: Vec<wal::Mutation>,
+    // The previous versions overwritten by the pending mutations, restored
+    // in reverse order if the WAL append fails.
+    undo: Vec<(Column, Key, Option<Value>)>,
+    records_since_checkpoint: usize,
+    checkpoint_interval: usize,
+}
+
+impl DiskEngine {
+    // Opens the engine stored in `dir`, replaying the checkpoint and the WAL.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<DiskEngine> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut table = KvTable::default();
+        let checkpoint = dir.join(CHECKPOINT_FILE);
+        if checkpoint.exists() {
+            let buf = fs::read(&checkpoint)?;
+            let batch: wal::Batch = labcodec::decode(&buf).map_err(invalid_data)?;
+            apply(&mut table, batch)?;
+        }
+
+        let mut wal = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(dir.join(WAL_FILE))?;
+        let mut buf = vec![];
+        wal.read_to_end(&mut buf)?;
+        let mut offset = 0;
+        let mut records = 0;
+        while let Some((batch, len)) = read_frame(&buf[offset..])? {
+            apply(&mut table, batch)?;
+            offset += len;
+            records += 1;
+        }
+        if offset < buf.len() {
+            // The tail was torn by a crash in the middle of an append.
+            wal.set_len(offset as u64)?;
+            wal.sync_data()?;
+        }
+
+        Ok(DiskEngine {
+            table,
+            dir,
+            wal,
+            pending: vec![],
+            undo: vec![],
+            records_since_checkpoint: records,
+            checkpoint_interval: CHECKPOINT_INTERVAL,
+        })
+    }
+
+    pub fn set_checkpoint_interval(&mut self, interval: usize) {
+        self.checkpoint_interval = interval;
+    }
+
+    // Writes the whole table to a new checkpoint and truncates the WAL.
+    pub fn checkpoint(&mut self) -> io::Result<()> {
+        self.sync_pending()?;
+
+        let mut batch = wal::Batch::default();
+        for (column, map) in &[
+            (Column::Write, &self.table.write),
+            (Column::Data, &self.table.data),
+            (Column::Lock, &self.table.lock),
+        ] {
+            for (map_key, value) in map.iter() {
+                batch
+                    .mutations
+                    .push(put_mutation(map_key.0.clone(), *column, map_key.1, value));
+            }
+        }
+        let mut buf = vec![];
+        labcodec::encode(&batch, &mut buf).map_err(invalid_data)?;
+
+        let tmp = self.dir.join(CHECKPOINT_TMP_FILE);
+        let mut file = File::create(&tmp)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join(CHECKPOINT_FILE))?;
+        // The rename must be durable before the WAL is emptied, or a crash
+        // could leave the old checkpoint next to an empty WAL.
+        File::open(&self.dir)?.sync_all()?;
+
+        // Replaying the old WAL on top of the new checkpoint is harmless, so
+        // a crash before the truncation below loses nothing.
+        self.wal.set_len(0)?;
+        self.wal.sync_data()?;
+        self.records_since_checkpoint = 0;
+        Ok(())
+    }
+
+    fn sync_pending(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        if let Err(e) = self.append_pending() {
+            // The mutations were never made durable, so none of them may stay
+            // visible or be appended by a later sync.
+            for (column, map_key, value) in self.undo.drain(..).rev() {
+                match value {
+                    Some(value) => self.table.write(map_key.0, column, map_key.1, value),
+                    None => self.table.remove(map_key.0, column, map_key.1),
+                }
+            }
+            self.pending.clear();
+            return Err(e);
+        }
+        self.pending.clear();
+        self.undo.clear();
+        self.records_since_checkpoint += 1;
+        Ok(())
+    }
+
+    fn append_pending(&mut self) -> io::Result<()> {
+        let batch = wal::Batch {
+            mutations: self.pending.clone(),
+        };
+        let mut buf = vec![];
+        labcodec::encode(&batch, &mut buf).map_err(invalid_data)?;
+        let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + buf.len());
+        frame.extend_from_slice(&(buf.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&crc32(&buf).to_le_bytes());
+        frame.extend_from_slice(&buf);
+
+        let len = self.wal.metadata()?.len();
+        if let Err(e) = self
+            .wal
+            .write_all(&frame)
+            .and_then(|_| self.wal.sync_data())
+        {
+            // Drop the partial record.
+            let _ = self.wal.set_len(len);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn save_undo(&mut self, key: &[u8], column: Column, ts: TimeStamp) {
+        let old = self.table.read(key.to_vec(), column, Some(ts), Some(ts));
+        self.undo.push(match old {
+            Some((map_key, value)) => (column, map_key, Some(value)),
+            None => (column, (key.to_vec(), ts), None),
+        });
+    }
+}
+
+impl Engine for DiskEngine {
+    fn read(
+        &self,
+        key: Vec<u8>,
+        column: Column,
+        ts_start_inclusive: Option<TimeStamp>,
+        ts_end_inclusive: Option<TimeStamp>,
+    ) -> Option<(Key, Value)> {
+        self.table
+            .read(key, column, ts_start_inclusive, ts_end_inclusive)
+    }
+
+    fn write(&mut self, key: Vec<u8>, column: Column, ts: TimeStamp, value: Value) {
+        self.save_undo(&key, column, ts);
+        self.pending
+            .push(put_mutation(key.clone(), column, ts, &value));
+        self.table.write(key, column, ts, value);
+    }
+
+    fn erase(&mut self, key: Vec<u8>, column: Column, commit_ts: TimeStamp) {
+        self.pending.push(wal::Mutation {
+            op: mutation::Op::Erase as i32,
+            column: encode_column(column) as i32,
+            key: key.clone(),
+            ts: commit_ts.into(),
+            value: None,
+        });
+        let erased: Vec<(Key, Value)> = self
+            .table
+            .range(
+                column,
+                Bound::Included((key.clone(), TimeStamp::ZERO)),
+                Bound::Included((key.clone(), commit_ts)),
+            )
+            .collect();
+        for (map_key, value) in erased {
+            self.undo.push((column, map_key, Some(value)));
+        }
+        self.table.erase(key, column, commit_ts);
+    }
+
+    fn remove(&mut self, key: Vec<u8>, column: Column, ts: TimeStamp) {
+        self.save_undo(&key, column, ts);
+        self.pending.push(wal::Mutation {
+            op: mutation::Op::Remove as i32,
+            column: encode_column(column) as i32,
+            key: key.clone(),
+            ts: ts.into(),
+            value: None,
+        });
+        self.table.remove(key, column, ts);
+    }
+
+    fn range<'a>(
+        &'a self,
+        column: Column,
+        start: Bound<Key>,
+        end: Bound<Key>,
+    ) -> Box<dyn DoubleEndedIterator<Item = (Key, Value)> + 'a> {
+        self.table.range(column, start, end)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_pending()?;
+        if self.records_since_checkpoint >= self.checkpoint_interval {
+            // The mutations are durable in the WAL already, a failed
+            // checkpoint is retried on the next sync.
+            let _ = self.checkpoint();
+        }
+        Ok(())
+    }
+}
+
+// Returns the record at the start of `buf` and its length, or None if `buf`
+// ends before the record does, which only a torn append leaves behind. A
+// complete record that fails its checksum is an error.
+fn read_frame(buf: &[u8]) -> io::Result<Option<(wal::Batch, usize)>> {
+    if buf.len() < FRAME_HEADER_LEN {
+        return Ok(None);
+    }
+    let mut word = [0; 4];
+    word.copy_from_slice(&buf[..4]);
+    let len = u32::from_le_bytes(word) as usize;
+    word.copy_from_slice(&buf[4..FRAME_HEADER_LEN]);
+    let checksum = u32::from_le_bytes(word);
+    if buf.len() < FRAME_HEADER_LEN + len {
+        return Ok(None);
+    }
+    let record = &buf[FRAME_HEADER_LEN..FRAME_HEADER_LEN + len];
+    if crc32(record) != checksum {
+        return Err(invalid_data("corrupted WAL record"));
+    }
+    let batch = labcodec::decode(record).map_err(invalid_data)?;
+    Ok(Some((batch, FRAME_HEADER_LEN + len)))
+}
+
+// The CRC-32 (IEEE) of `buf`, computed bit by bit as the records are small.
+fn crc32(buf: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in buf {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+fn apply(table: &mut KvTable, batch: wal::Batch) -> io::Result<()> {
+    for m in batch.mutations {
+        let ts = TimeStamp::from(m.ts);
+        let column = match wal::Column::from_i32(m.column) {
+            Some(wal::Column::Write) => Column::Write,
+            Some(wal::Column::Data) => Column::Data,
+            Some(wal::Column::Lock) => Column::Lock,
+            None => return Err(invalid_data("unknown column")),
+        };
+        match mutation::Op::from_i32(m.op) {
+            Some(mutation::Op::Put) => {
+                let value = match m.value {
+                    Some(mutation::Value::Vector(v)) => Value::Vector(v),
+                    Some(mutation::Value::Write(w)) => {
+                        Value::Write(decode_kind(w.kind)?, w.start_ts.into())
+                    }
+                    Some(mutation::Value::Lock(l)) => Value::Lock(Lock {
+                        primary: l.primary,
+                        kind: decode_kind(l.kind)?,
+                        ttl: l.ttl,
+                        txn_size: l.txn_size,
+                        min_commit_ts: l.min_commit_ts.into(),
+                        secondaries: l.secondaries,
+                    }),
+                    None => return Err(invalid_data("missing value")),
+                };
+                table.write(m.key, column, ts, value);
+            }
+            Some(mutation::Op::Erase) => table.erase(m.key, column, ts),
+            Some(mutation::Op::Remove) => table.remove(m.key, column, ts),
+            None => return Err(invalid_data("unknown op")),
+        }
+    }
+    Ok(())
+}
+
+fn put_mutation(key: Vec<u8>, column: Column, ts: TimeStamp, value: &Value) -> wal::Mutation {
+    let value = match value {
+        Value::Write(kind, start_ts) => mutation::Value::Write(wal::WriteRecord {
+            kind: encode_kind(*kind) as i32,
+            start_ts: (*start_ts).into(),
+        }),
+        Value::Vector(v) => mutation::Value::Vector(v.clone()),
+        Value::Lock(lock) => mutation::Value::Lock(wal::LockRecord {
+            primary: lock.primary.clone(),
+            kind: encode_kind(lock.kind) as i32,
+            ttl: lock.ttl,
+            txn_size: lock.txn_size,
+            min_commit_ts: lock.min_commit_ts.into(),
+            secondaries: lock.secondaries.clone(),
+        }),
+    };
+    wal::Mutation {
+        op: mutation::Op::Put as i32,
+        column: encode_column(column) as i32,
+        key,
+        ts: ts.into(),
+        value: Some(value),
+    }
+}
+
+fn encode_column(column: Column) -> wal::Column {
+    match column {
+        Column::Write => wal::Column::Write,
+        Column::Data => wal::Column::Data,
+        Column::Lock => wal::Column::Lock,
+    }
+}
+
+fn encode_kind(kind: WriteKind) -> write_record::Kind {
+    match kind {
+        WriteKind::Put => write_record::Kind::Put,
+        WriteKind::Delete => write_record::Kind::Delete,
+        WriteKind::Lock => write_record::Kind::Lock,
+        WriteKind::Rollback => write_record::Kind::Rollback,
+    }
+}
+
+fn decode_kind(kind: i32) -> io::Result<WriteKind> {
+    match write_record::Kind::from_i32(kind) {
+        Some(write_record::Kind::Put) => Ok(WriteKind::Put),
+        Some(write_record::Kind::Delete) => Ok(WriteKind::Delete),
+        Some(write_record::Kind::Lock) => Ok(WriteKind::Lock),
+        Some(write_record::Kind::Rollback) => Ok(WriteKind::Rollback),
+        None => Err(invalid_data("unknown write kind")),
+    }
+}
+
+fn invalid_data<E: std::fmt::Debug>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e))
+}
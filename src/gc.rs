@@ -0,0 +1,104 @@
+use std::io;
+use std::ops::Bound;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::{Column, Engine, MemoryStorage, TimeStamp, WriteKind};
+
+// GcWorker garbage collects the storage in the background at the latest
+// safe point. It stops when dropped.
+pub struct GcWorker {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for GcWorker {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl<E: Engine> MemoryStorage<E> {
+    // Removes the versions of every key that no snapshot at or above
+    // `safe_point` can see, and returns how many were reclaimed. The newest
+    // version visible at the safe point is kept, and keys with locks are
+    // left alone since their transactions may still read or commit them.
+    pub fn run_gc(&self, safe_point: TimeStamp) -> io::Result<u64> {
+        let mut kv_data = self.data.lock().unwrap();
+
+        let mut keys: Vec<Vec<u8>> = vec![];
+        for ((key, _), _) in kv_data.range(Column::Write, Bound::Unbounded, Bound::Unbounded) {
+            if keys.last() != Some(&key) {
+                keys.push(key);
+            }
+        }
+
+        let mut reclaimed = 0;
+        for key in keys {
+            if kv_data
+                .read(key.clone(), Column::Lock, None, None)
+                .is_some()
+            {
+                continue;
+            }
+            let newest = kv_data
+                .range(
+                    Column::Write,
+                    Bound::Included((key.clone(), TimeStamp::ZERO)),
+                    Bound::Included((key.clone(), safe_point)),
+                )
+                .rev()
+                .map(|(map_key, value)| (map_key.1, value.unwrap_write()))
+                .find(|(_, (kind, _))| *kind != WriteKind::Lock && *kind != WriteKind::Rollback);
+            let (write_ts, data_ts) = match newest {
+                // The newest put stays visible to snapshots at the safe point.
+                Some((commit_ts, (WriteKind::Put, start_ts))) => (commit_ts - 1, start_ts - 1),
+                // Nothing is visible below the newest delete, so it goes too.
+                Some((commit_ts, _)) => (commit_ts, commit_ts),
+                None => continue,
+            };
+            let versions = kv_data
+                .range(
+                    Column::Write,
+                    Bound::Included((key.clone(), TimeStamp::ZERO)),
+                    Bound::Included((key.clone(), write_ts)),
+                )
+                .count();
+            if versions == 0 {
+                continue;
+            }
+            kv_data.erase(key.clone(), Column::Write, write_ts);
+            kv_data.erase(key, Column::Data, data_ts);
+            reclaimed += versions as u64;
+        }
+
+        kv_data.sync()?;
+        Ok(reclaimed)
+    }
+
+    // Starts a worker that runs GC at the current safe point every `interval`.
+    pub fn start_gc_worker(&self, interval: Duration) -> GcWorker {
+        let (tx, rx) = mpsc::channel();
+        let storage = self.clone();
+        let handle = thread::spawn(move || loop {
+            match rx.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => {
+                    let safe_point = TimeStamp::from(storage.safe_point.load(Ordering::SeqCst));
+                    if safe_point > TimeStamp::ZERO {
+                        let _ = storage.run_gc(safe_point);
+                    }
+                }
+                _ => return,
+            }
+        });
+        GcWorker {
+            stop: Some(tx),
+            handle: Some(handle),
+        }
+    }
+}
@@ -1,4 +1,7 @@
-use std::time::Duration;
+use std::fs::{self, File};
+use std::io::{self, Write as IoWrite};
+use std::path::Path;
+use std::sync::atomic::Ordering;
 
 use crate::msg::*;
 use crate::service::*;
@@ -6,261 +9,773 @@ use crate::*;
 
 use labrpc::{Error, RpcFuture};
 
-const MAX_TIME_TO_ALIVE: u64 = Duration::from_millis(100).as_nanos() as u64;
+// The TTL in milliseconds of the locks whose prewrite does not set one.
+const DEFAULT_LOCK_TTL: u64 = 100;
+const TSO_LIMIT_FILE: &str = "tso_limit";
+const TSO_LIMIT_TMP_FILE: &str = "tso_limit.tmp";
 
 impl KvTable {
+    #[inline]
+    fn column(&self, column: Column) -> &BTreeMap<Key, Value> {
+        match column {
+            Column::Write => &self.write,
+            Column::Data => &self.data,
+            Column::Lock => &self.lock,
+        }
+    }
+
+    #[inline]
+    fn column_mut(&mut self, column: Column) -> &mut BTreeMap<Key, Value> {
+        match column {
+            Column::Write => &mut self.write,
+            Column::Data => &mut self.data,
+            Column::Lock => &mut self.lock,
+        }
+    }
+}
+
+impl Engine for KvTable {
     #[inline]
     fn read(
         &self,
         key: Vec<u8>,
         column: Column,
-        ts_start_inclusive: Option<u64>,
-        ts_end_inclusive: Option<u64>,
-    ) -> Option<(&Key, &Value)> {
+        ts_start_inclusive: Option<TimeStamp>,
+        ts_end_inclusive: Option<TimeStamp>,
+    ) -> Option<(Key, Value)> {
         let range_start = match ts_start_inclusive {
-            None => (key.clone(), 0),
+            None => (key.clone(), TimeStamp::ZERO),
             Some(ts) => (key.clone(), ts),
         };
         let range_end = match ts_end_inclusive {
-            None => (key.clone(), std::u64::MAX),
+            None => (key.clone(), TimeStamp::MAX),
             Some(ts) => (key.clone(), ts),
         };
-        let mut r = match column {
-            Column::Write => self.write.range(range_start..=range_end),
-            Column::Data => self.data.range(range_start..=range_end),
-            Column::Lock => self.lock.range(range_start..=range_end),
-        };
-        r.next_back()
+        self.column(column)
+            .range(range_start..=range_end)
+            .next_back()
+            .map(|(k, v)| (k.clone(), v.clone()))
     }
 
     #[inline]
-    fn write(&mut self, key: Vec<u8>, column: Column, ts: u64, value: Value) {
-        let map_key = (key, ts);
-        match column {
-            Column::Write => {
-                let _ = self.write.insert(map_key, value);
-            }
-            Column::Data => {
-                let _ = self.data.insert(map_key, value);
-            }
-            Column::Lock => {
-                let _ = self.lock.insert(map_key, value);
-            }
+    fn write(&mut self, key: Vec<u8>, column: Column, ts: TimeStamp, value: Value) {
+        let _ = self.column_mut(column).insert((key, ts), value);
+    }
+
+    #[inline]
+    fn erase(&mut self, key: Vec<u8>, column: Column, commit_ts: TimeStamp) {
+        let map = self.column_mut(column);
+        let keys: Vec<Key> = map
+            .range((key.clone(), TimeStamp::ZERO)..=(key, commit_ts))
+            .map(|(map_key, _)| map_key.clone())
+            .collect();
+        for map_key in keys {
+            let _ = map.remove(&map_key);
         }
     }
 
     #[inline]
-    fn erase(&mut self, key: Vec<u8>, column: Column, commit_ts: u64) {
-        match column {
-            Column::Data => {
-                let l = self.data.clone();
-                for (map_key, _) in l.iter() {
-                    if key.as_slice() == map_key.0.as_slice() && map_key.1 <= commit_ts {
-                        let _ = self.data.remove(&map_key);
-                    }
-                }
+    fn remove(&mut self, key: Vec<u8>, column: Column, ts: TimeStamp) {
+        let _ = self.column_mut(column).remove(&(key, ts));
+    }
+
+    #[inline]
+    fn range<'a>(
+        &'a self,
+        column: Column,
+        start: Bound<Key>,
+        end: Bound<Key>,
+    ) -> Box<dyn DoubleEndedIterator<Item = (Key, Value)> + 'a> {
+        Box::new(
+            self.column(column)
+                .range((start, end))
+                .map(|(k, v)| (k.clone(), v.clone())),
+        )
+    }
+}
+
+impl<E: Engine> transaction::Service for MemoryStorage<E> {
+    fn get(&self, req: GetRequest) -> RpcFuture<GetResponse> {
+        let kv_data = self.data.lock().unwrap();
+
+        match read_value(&*kv_data, req.key.clone(), req.start_ts.into()) {
+            Ok(v) => Box::new(futures::future::result(Ok(GetResponse {
+                not_found: v.is_none(),
+                value: v.unwrap_or_default(),
+                error: None,
+            }))),
+            Err(lock) => Box::new(futures::future::result(Ok(GetResponse {
+                value: vec![],
+                error: Some(key_error::Error::Locked(lock).into()),
+                not_found: false,
+            }))),
+        }
+    }
+
+    fn scan(&self, req: ScanRequest) -> RpcFuture<ScanResponse> {
+        // An inverted range would make the engine panic with the lock held.
+        if !req.end_key.is_empty() && req.start_key >= req.end_key {
+            return Box::new(futures::future::result(Ok(ScanResponse {
+                pairs: vec![],
+                error: None,
+            })));
+        }
+        let kv_data = self.data.lock().unwrap();
+
+        let mut lower = Bound::Included((req.start_key, TimeStamp::ZERO));
+        let mut upper = if req.end_key.is_empty() {
+            Bound::Unbounded
+        } else {
+            Bound::Excluded((req.end_key, TimeStamp::ZERO))
+        };
+        let mut pairs = vec![];
+        while req.limit == 0 || pairs.len() < req.limit as usize {
+            let key = match next_key(&*kv_data, &lower, &upper, req.reverse) {
+                Some(key) => key,
+                None => break,
+            };
+            if req.reverse {
+                upper = Bound::Excluded((key.clone(), TimeStamp::ZERO));
+            } else {
+                lower = Bound::Excluded((key.clone(), TimeStamp::MAX));
             }
-            Column::Lock => {
-                let l = self.lock.clone();
-                for (map_key, _) in l.iter() {
-                    if key.as_slice() == map_key.0.as_slice() && map_key.1 <= commit_ts {
-                        let _ = self.lock.remove(&map_key);
-                    }
+            match read_value(&*kv_data, key.clone(), req.start_ts.into()) {
+                Ok(Some(value)) => pairs.push(KvPair { key, value }),
+                Ok(None) => {}
+                Err(lock) => {
+                    return Box::new(futures::future::result(Ok(ScanResponse {
+                        pairs: vec![],
+                        error: Some(key_error::Error::Locked(lock).into()),
+                    })));
                 }
             }
-            _ => {}
         }
+
+        Box::new(futures::future::result(Ok(ScanResponse {
+            pairs,
+            error: None,
+        })))
     }
 
-    #[inline]
-    fn get_uncommitted_keys(&self, ts: u64, primary: Vec<u8>) -> Vec<Key> {
-        let mut keys: Vec<Key> = vec![];
-        for (map_key, v) in self.lock.iter() {
-            if (*v).clone().unwrap_vec() == primary && map_key.1 == ts {
-                keys.push((*map_key).clone());
+    // Prewrite tries to lock every cell of the request, leaving them all
+    // untouched in case of conflict.
+    fn prewrite(&self, req: PrewriteRequest) -> RpcFuture<PrewriteResponse> {
+        let mut kv_data = self.data.lock().unwrap();
+        let mut kinds = Vec::with_capacity(req.mutations.len());
+        for m in &req.mutations {
+            match Op::from_i32(m.op) {
+                Some(op) => kinds.push(WriteKind::from(op)),
+                None => {
+                    return Box::new(futures::future::result(Err(Error::Other(
+                        "unknown op".to_string(),
+                    ))));
+                }
             }
         }
+        let lock_ttl = if req.lock_ttl == 0 {
+            DEFAULT_LOCK_TTL
+        } else {
+            req.lock_ttl
+        };
+        let start_ts = TimeStamp::from(req.start_ts);
 
-        keys
-    }
+        for m in &req.mutations {
+            if let Some(error) = check_prewrite(&*kv_data, m.key.clone(), start_ts, &req.primary) {
+                return Box::new(futures::future::result(Ok(PrewriteResponse {
+                    error: Some(error),
+                })));
+            }
+        }
 
-    #[inline]
-    fn get_commit_ts(&self, ts: u64, primary: Vec<u8>) -> Option<u64> {
-        for (map_key, v) in self.write.iter() {
-            if (*v).clone().unwrap_ts() == ts && map_key.0 == primary {
-                return Some(map_key.1);
+        for (m, kind) in req.mutations.into_iter().zip(kinds) {
+            if kind == WriteKind::Put {
+                kv_data.write(
+                    m.key.clone(),
+                    Column::Data,
+                    start_ts,
+                    Value::Vector(m.value),
+                );
             }
+            // Only the primary lock keeps track of the secondaries.
+            let secondaries = if m.key == req.primary {
+                req.secondaries.clone()
+            } else {
+                vec![]
+            };
+            kv_data.write(
+                m.key,
+                Column::Lock,
+                start_ts,
+                Value::Lock(Lock {
+                    primary: req.primary.clone(),
+                    kind,
+                    ttl: lock_ttl,
+                    txn_size: req.txn_size,
+                    min_commit_ts: start_ts + 1,
+                    secondaries,
+                }),
+            );
         }
 
-        None
+        Box::new(futures::future::result(
+            kv_data
+                .sync()
+                .map(|_| PrewriteResponse { error: None })
+                .map_err(|e| Error::Other(e.to_string())),
+        ))
     }
-}
 
-impl transaction::Service for MemoryStorage {
-    fn get(&self, req: GetRequest) -> RpcFuture<GetResponse> {
-        let key = req.key.clone();
-        let snapshot = self.get_snapshot();
-
-        if snapshot
-            .read(key.clone(), Column::Lock, None, Some(req.start_ts))
-            .is_some()
-        {
-            // Check for locks that signal concurrent writes.
-            self.back_off_maybe_clean_up_lock(req.start_ts, key.clone());
-            return Box::new(futures::future::result(Err(Error::Other(
-                "Backoff".to_string(),
-            ))));
-        }
-
-        // Find the latest write below our start timestamp.
-        let data_ts = match snapshot.read(key.clone(), Column::Write, None, Some(req.start_ts)) {
-            Some(res) => (*(res.1)).clone().unwrap_ts(),
-            None => {
-                return Box::new(futures::future::result(Ok(GetResponse {
-                    value: Vec::new(),
-                })))
+    fn commit(&self, req: CommitRequest) -> RpcFuture<CommitResponse> {
+        let mut kv_data = self.data.lock().unwrap();
+        let start_ts = TimeStamp::from(req.start_ts);
+        for key in &req.keys {
+            if is_rolled_back(&*kv_data, key.clone(), start_ts) {
+                return Box::new(futures::future::result(Ok(CommitResponse {
+                    error: Some(
+                        key_error::Error::Aborted(Aborted {
+                            start_ts: req.start_ts,
+                        })
+                        .into(),
+                    ),
+                })));
             }
-        };
-        let v = match snapshot.read(key.clone(), Column::Data, Some(data_ts), Some(data_ts)) {
-            Some(res) => (*(res.1)).clone().unwrap_vec(),
-            None => {
-                return Box::new(futures::future::result(Ok(GetResponse {
-                    value: Vec::new(),
-                })))
+            if !req.is_primary {
+                continue;
             }
-        };
+            // A secondary without a lock has already been resolved by a reader.
+            let error =
+                match kv_data.read(key.clone(), Column::Lock, Some(start_ts), Some(start_ts)) {
+                    None => key_error::Error::TxnNotFound(TxnNotFound {
+                        start_ts: req.start_ts,
+                        key: key.clone(),
+                    }),
+                    Some((_, lock)) => {
+                        let min_commit_ts = lock.unwrap_lock().min_commit_ts;
+                        if TimeStamp::from(req.commit_ts) >= min_commit_ts {
+                            continue;
+                        }
+                        key_error::Error::CommitTsExpired(CommitTsExpired {
+                            start_ts: req.start_ts,
+                            commit_ts: req.commit_ts,
+                            min_commit_ts: min_commit_ts.into(),
+                        })
+                    }
+                };
+            return Box::new(futures::future::result(Ok(CommitResponse {
+                error: Some(error.into()),
+            })));
+        }
 
-        Box::new(futures::future::result(Ok(GetResponse { value: v })))
+        for key in req.keys {
+            commit_key(&mut *kv_data, key, start_ts, req.commit_ts.into());
+        }
+
+        Box::new(futures::future::result(
+            kv_data
+                .sync()
+                .map(|_| CommitResponse { error: None })
+                .map_err(|e| Error::Other(e.to_string())),
+        ))
     }
 
-    // Prewrite tries to lock cell w, returning false in case of conflict.
-    fn prewrite(&self, req: PrewriteRequest) -> RpcFuture<PrewriteResponse> {
+    fn rollback(&self, req: RollbackRequest) -> RpcFuture<RollbackResponse> {
         let mut kv_data = self.data.lock().unwrap();
 
-        if kv_data
-            .read(
-                req.write.as_ref().unwrap().key.clone(),
-                Column::Write,
-                Some(req.start_ts),
-                None,
-            )
-            .is_some()
+        let start_ts = TimeStamp::from(req.start_ts);
+        if let Some(commit_ts) = req
+            .keys
+            .iter()
+            .find_map(|key| kv_data.get_commit_ts(start_ts, key.clone()))
         {
-            // Abort on writes after our start timestamp ...
-            return Box::new(futures::future::result(Err(Error::Other(
-                "write conflict".to_string(),
-            ))));
+            return Box::new(futures::future::result(Ok(RollbackResponse {
+                error: Some(
+                    key_error::Error::Committed(Committed {
+                        commit_ts: commit_ts.into(),
+                    })
+                    .into(),
+                ),
+            })));
         }
 
-        if kv_data
+        for key in req.keys {
+            rollback_key(&mut *kv_data, key, start_ts);
+        }
+
+        Box::new(futures::future::result(
+            kv_data
+                .sync()
+                .map(|_| RollbackResponse { error: None })
+                .map_err(|e| Error::Other(e.to_string())),
+        ))
+    }
+
+    fn check_txn_status(&self, req: CheckTxnStatusRequest) -> RpcFuture<CheckTxnStatusResponse> {
+        let mut kv_data = self.data.lock().unwrap();
+        let lock_ts = TimeStamp::from(req.lock_ts);
+        let secondaries = kv_data
             .read(
-                req.write.as_ref().unwrap().key.clone(),
+                req.primary_key.clone(),
                 Column::Lock,
-                None,
-                None,
+                Some(lock_ts),
+                Some(lock_ts),
             )
-            .is_some()
-        {
-            // ... or locks at any timestamp.
-            return Box::new(futures::future::result(Err(Error::Other(
-                "key has already locked".to_string(),
-            ))));
+            .map(|(_, lock)| lock.unwrap_lock().secondaries)
+            .unwrap_or_default();
+        let status = check_txn_status(
+            &mut *kv_data,
+            req.primary_key,
+            req.lock_ts.into(),
+            req.caller_start_ts.into(),
+            req.current_ts.into(),
+        );
+        let resp = match status {
+            TxnStatus::Locked(lock_ttl) => CheckTxnStatusResponse {
+                status: check_txn_status_response::Status::Locked as i32,
+                commit_ts: 0,
+                lock_ttl,
+                secondaries,
+            },
+            TxnStatus::Committed(commit_ts) => CheckTxnStatusResponse {
+                status: check_txn_status_response::Status::Committed as i32,
+                commit_ts: commit_ts.into(),
+                lock_ttl: 0,
+                secondaries,
+            },
+            TxnStatus::RolledBack => CheckTxnStatusResponse {
+                status: check_txn_status_response::Status::RolledBack as i32,
+                commit_ts: 0,
+                lock_ttl: 0,
+                secondaries,
+            },
+        };
+
+        Box::new(futures::future::result(
+            kv_data
+                .sync()
+                .map(|_| resp)
+                .map_err(|e| Error::Other(e.to_string())),
+        ))
+    }
+
+    fn resolve_lock(&self, req: ResolveLockRequest) -> RpcFuture<ResolveLockResponse> {
+        let mut kv_data = self.data.lock().unwrap();
+        let start_ts = TimeStamp::from(req.start_ts);
+        let keys = if req.keys.is_empty() {
+            kv_data
+                .get_uncommitted_keys(start_ts)
+                .into_iter()
+                .map(|map_key| map_key.0)
+                .collect()
+        } else {
+            req.keys
+        };
+
+        for key in keys {
+            if req.commit_ts == 0 {
+                rollback_key(&mut *kv_data, key, start_ts);
+            } else {
+                commit_key(&mut *kv_data, key, start_ts, req.commit_ts.into());
+            }
         }
 
-        kv_data.write(
-            req.write.as_ref().unwrap().key.clone(),
-            Column::Data,
-            req.start_ts,
-            Value::Vector(req.write.as_ref().unwrap().value.clone()),
-        );
-        kv_data.write(
-            req.write.as_ref().unwrap().key.clone(),
+        Box::new(futures::future::result(
+            kv_data
+                .sync()
+                .map(|_| ResolveLockResponse {})
+                .map_err(|e| Error::Other(e.to_string())),
+        ))
+    }
+
+    fn txn_heartbeat(&self, req: TxnHeartBeatRequest) -> RpcFuture<TxnHeartBeatResponse> {
+        let mut kv_data = self.data.lock().unwrap();
+        let start_ts = TimeStamp::from(req.start_ts);
+        let lock = kv_data.read(
+            req.primary_key.clone(),
             Column::Lock,
-            req.start_ts,
-            Value::Vector(req.primary.unwrap().key.clone()),
+            Some(start_ts),
+            Some(start_ts),
         );
+        let mut lock = match lock {
+            Some((_, lock)) => lock.unwrap_lock(),
+            // The transaction has been committed or rolled back.
+            None => {
+                return Box::new(futures::future::result(Ok(TxnHeartBeatResponse {
+                    lock_ttl: 0,
+                    error: Some(
+                        key_error::Error::TxnNotFound(TxnNotFound {
+                            start_ts: req.start_ts,
+                            key: req.primary_key,
+                        })
+                        .into(),
+                    ),
+                })));
+            }
+        };
 
-        Box::new(futures::future::result(Ok(PrewriteResponse {})))
+        let lock_ttl = lock.ttl.max(req.advise_lock_ttl);
+        if lock_ttl > lock.ttl {
+            lock.ttl = lock_ttl;
+            kv_data.write(req.primary_key, Column::Lock, start_ts, Value::Lock(lock));
+        }
+
+        Box::new(futures::future::result(
+            kv_data
+                .sync()
+                .map(|_| TxnHeartBeatResponse {
+                    lock_ttl,
+                    error: None,
+                })
+                .map_err(|e| Error::Other(e.to_string())),
+        ))
     }
 
-    fn commit(&self, req: CommitRequest) -> RpcFuture<CommitResponse> {
-        let mut kv_data = self.data.lock().unwrap();
-        if req.is_primary
-            && kv_data
-                .read(
-                    req.write.as_ref().unwrap().key.clone(),
-                    Column::Lock,
-                    Some(req.start_ts),
-                    Some(req.start_ts),
-                )
-                .is_none()
-        {
-            // Lock is not found.
-            return Box::new(futures::future::result(Err(Error::Other(
-                "lock is not found".to_string(),
-            ))));
+    fn gc(&self, req: GcRequest) -> RpcFuture<GcResponse> {
+        // The safe point never moves backwards.
+        let safe_point = self
+            .safe_point
+            .fetch_max(req.safe_point, Ordering::SeqCst)
+            .max(req.safe_point);
+        Box::new(futures::future::result(
+            self.run_gc(safe_point.into())
+                .map(|reclaimed| GcResponse { reclaimed })
+                .map_err(|e| Error::Other(e.to_string())),
+        ))
+    }
+}
+
+// Returns the value of `key` visible at `start_ts`, or the lock that signals
+// a concurrent write.
+fn read_value<E: Engine>(
+    kv_data: &E,
+    key: Vec<u8>,
+    start_ts: TimeStamp,
+) -> Result<Option<Vec<u8>>, LockInfo> {
+    // Check for locks that signal concurrent writes.
+    if let Some((map_key, lock)) = kv_data.read(key.clone(), Column::Lock, None, Some(start_ts)) {
+        if !commits_after(kv_data, &lock, map_key.1, start_ts) {
+            return Err(lock_info(key, map_key.1, lock));
         }
+    }
 
-        kv_data.write(
-            req.write.as_ref().unwrap().key.clone(),
+    // Find the latest write below our start timestamp.
+    let write = kv_data
+        .range(
             Column::Write,
-            req.commit_ts,
-            Value::Timestamp(req.start_ts),
+            Bound::Included((key.clone(), TimeStamp::ZERO)),
+            Bound::Included((key.clone(), start_ts)),
+        )
+        .rev()
+        .map(|(_, v)| v.unwrap_write())
+        .find(|(kind, _)| *kind != WriteKind::Lock && *kind != WriteKind::Rollback);
+    match write {
+        Some((WriteKind::Put, data_ts)) => Ok(kv_data
+            .read(key, Column::Data, Some(data_ts), Some(data_ts))
+            .map(|res| res.1.unwrap_vec())),
+        _ => Ok(None),
+    }
+}
+
+// Returns whether the transaction at `lock_ts` that left `lock` can only
+// commit above `start_ts`, once its primary lock has been pushed past it.
+fn commits_after<E: Engine>(
+    kv_data: &E,
+    lock: &Value,
+    lock_ts: TimeStamp,
+    start_ts: TimeStamp,
+) -> bool {
+    let primary = lock.clone().unwrap_lock().primary;
+    match kv_data.read(primary, Column::Lock, Some(lock_ts), Some(lock_ts)) {
+        Some((_, primary_lock)) => primary_lock.unwrap_lock().min_commit_ts > start_ts,
+        None => false,
+    }
+}
+
+// Returns the first key within the bounds that has a write record or a lock,
+// the last one when scanning in reverse.
+fn next_key<E: Engine>(
+    kv_data: &E,
+    lower: &Bound<Key>,
+    upper: &Bound<Key>,
+    reverse: bool,
+) -> Option<Vec<u8>> {
+    let mut next: Option<Vec<u8>> = None;
+    for column in &[Column::Write, Column::Lock] {
+        let mut r = kv_data.range(*column, lower.clone(), upper.clone());
+        let key = match if reverse { r.next_back() } else { r.next() } {
+            Some((map_key, _)) => map_key.0,
+            None => continue,
+        };
+        next = match next {
+            Some(n) if (n < key) != reverse => Some(n),
+            _ => Some(key),
+        };
+    }
+    next
+}
+
+// Checks that `key` can be prewritten by the transaction at `start_ts`.
+fn check_prewrite<E: Engine>(
+    kv_data: &E,
+    key: Vec<u8>,
+    start_ts: TimeStamp,
+    primary: &[u8],
+) -> Option<KeyError> {
+    if is_rolled_back(kv_data, key.clone(), start_ts) {
+        return Some(
+            key_error::Error::Aborted(Aborted {
+                start_ts: start_ts.into(),
+            })
+            .into(),
         );
-        kv_data.erase(
-            req.write.as_ref().unwrap().key.clone(),
-            Column::Lock,
-            req.commit_ts,
+    }
+
+    // Abort on writes after our start timestamp ...
+    if let Some((map_key, _)) = kv_data.read(key.clone(), Column::Write, Some(start_ts), None) {
+        return Some(
+            key_error::Error::Conflict(WriteConflict {
+                start_ts: start_ts.into(),
+                conflict_ts: map_key.1.into(),
+                key,
+                primary: primary.to_vec(),
+            })
+            .into(),
         );
+    }
 
-        Box::new(futures::future::result(Ok(CommitResponse {})))
+    // ... or locks at any timestamp.
+    if let Some((map_key, lock)) = kv_data.read(key.clone(), Column::Lock, None, None) {
+        return Some(key_error::Error::Locked(lock_info(key, map_key.1, lock)).into());
     }
+
+    None
 }
 
-impl MemoryStorage {
-    fn get_snapshot(&self) -> KvTable {
-        self.data.lock().unwrap().clone()
+fn lock_info(key: Vec<u8>, start_ts: TimeStamp, lock: Value) -> LockInfo {
+    let lock = lock.unwrap_lock();
+    LockInfo {
+        primary: lock.primary,
+        start_ts: start_ts.into(),
+        key,
+        lock_ttl: lock.ttl,
+        lock_type: Op::from(lock.kind) as i32,
     }
+}
 
-    fn back_off_maybe_clean_up_lock(&self, start_ts: u64, key: Vec<u8>) {
-        let mut kv_data = self.data.lock().unwrap();
-        if let Some(r) = kv_data.read(key.clone(), Column::Lock, None, Some(start_ts)) {
-            let now = time::SystemTime::now();
-            let current_ts = now.duration_since(time::UNIX_EPOCH).expect("").as_nanos() as u64;
-            if current_ts - (*r.0).1 > MAX_TIME_TO_ALIVE {
-                let primary = (*r.1).clone().unwrap_vec().clone();
-                let ts = (*r.0).1;
-                if kv_data
-                    .read(primary.clone(), Column::Lock, Some(ts), Some(ts))
-                    .is_some()
-                {
-                    let uncommitted_keys = kv_data.get_uncommitted_keys(ts, primary);
-
-                    for k in uncommitted_keys {
-                        kv_data.erase(k.0.clone(), Column::Data, ts);
-                        kv_data.erase(k.0.clone(), Column::Lock, ts);
-                    }
-                } else {
-                    let uncommitted_keys = kv_data.get_uncommitted_keys(ts, primary.clone());
-                    let commit_ts = kv_data.get_commit_ts(ts, primary).unwrap();
+// Commits the lock of the transaction at `start_ts` on `key`, returning
+// false if there is no such lock.
+fn commit_key<E: Engine>(
+    kv_data: &mut E,
+    key: Vec<u8>,
+    start_ts: TimeStamp,
+    commit_ts: TimeStamp,
+) -> bool {
+    let kind = match kv_data.read(key.clone(), Column::Lock, Some(start_ts), Some(start_ts)) {
+        Some((_, lock)) => lock.unwrap_lock().kind,
+        None => return false,
+    };
+    kv_data.write(
+        key.clone(),
+        Column::Write,
+        commit_ts,
+        Value::Write(kind, start_ts),
+    );
+    kv_data.erase(key, Column::Lock, commit_ts);
+    true
+}
 
-                    for k in uncommitted_keys {
-                        kv_data.write(k.0.clone(), Column::Write, commit_ts, Value::Timestamp(ts));
-                        kv_data.erase(k.0.clone(), Column::Lock, commit_ts);
-                    }
+// Rolls back the transaction at `start_ts` on `key` and leaves a rollback
+// record behind, so that a delayed prewrite or commit cannot revive it.
+fn rollback_key<E: Engine>(kv_data: &mut E, key: Vec<u8>, start_ts: TimeStamp) {
+    if kv_data
+        .read(key.clone(), Column::Lock, Some(start_ts), Some(start_ts))
+        .is_some()
+    {
+        kv_data.remove(key.clone(), Column::Lock, start_ts);
+        kv_data.remove(key.clone(), Column::Data, start_ts);
+    }
+    kv_data.write(
+        key,
+        Column::Write,
+        start_ts,
+        Value::Write(WriteKind::Rollback, start_ts),
+    );
+}
+
+// Checks the state of the transaction at `lock_ts` through its primary key.
+// A transaction whose primary lock has expired at `current_ts`, or never
+// made it to the primary, is rolled back so that it can no longer commit.
+// A live transaction is pushed to commit above `caller_start_ts`, so that
+// the caller can read past its locks; nothing commits above the maximum
+// timestamp, so a caller there waits out the lock instead.
+fn check_txn_status<E: Engine>(
+    kv_data: &mut E,
+    primary: Vec<u8>,
+    lock_ts: TimeStamp,
+    caller_start_ts: TimeStamp,
+    current_ts: TimeStamp,
+) -> TxnStatus {
+    if let Some((_, lock)) =
+        kv_data.read(primary.clone(), Column::Lock, Some(lock_ts), Some(lock_ts))
+    {
+        let mut lock = lock.unwrap_lock();
+        let ttl = lock.ttl;
+        let elapsed = current_ts.elapsed_ms_since(lock_ts);
+        if elapsed <= ttl {
+            if caller_start_ts >= lock.min_commit_ts && caller_start_ts < TimeStamp::MAX {
+                lock.min_commit_ts = caller_start_ts + 1;
+                kv_data.write(primary, Column::Lock, lock_ts, Value::Lock(lock));
+            }
+            return TxnStatus::Locked(ttl - elapsed);
+        }
+    } else if let Some(commit_ts) = kv_data.get_commit_ts(lock_ts, primary.clone()) {
+        return TxnStatus::Committed(commit_ts);
+    }
+
+    rollback_key(kv_data, primary, lock_ts);
+    TxnStatus::RolledBack
+}
+
+fn is_rolled_back<E: Engine>(kv_data: &E, key: Vec<u8>, start_ts: TimeStamp) -> bool {
+    match kv_data.read(key, Column::Write, Some(start_ts), Some(start_ts)) {
+        Some((_, v)) => v.unwrap_write().0 == WriteKind::Rollback,
+        None => false,
+    }
+}
+
+impl<E: Engine> Clone for MemoryStorage<E> {
+    fn clone(&self) -> Self {
+        MemoryStorage {
+            data: self.data.clone(),
+            safe_point: self.safe_point.clone(),
+        }
+    }
+}
+
+impl<E: Engine> MemoryStorage<E> {
+    pub fn new(engine: E) -> MemoryStorage<E> {
+        MemoryStorage {
+            data: Arc::new(Mutex::new(engine)),
+            safe_point: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+impl From<key_error::Error> for KeyError {
+    fn from(error: key_error::Error) -> KeyError {
+        KeyError { error: Some(error) }
+    }
+}
+
+impl From<Op> for WriteKind {
+    fn from(op: Op) -> WriteKind {
+        match op {
+            Op::Put => WriteKind::Put,
+            Op::Delete => WriteKind::Delete,
+            Op::Lock => WriteKind::Lock,
+        }
+    }
+}
+
+impl From<WriteKind> for Op {
+    fn from(kind: WriteKind) -> Op {
+        match kind {
+            WriteKind::Put => Op::Put,
+            WriteKind::Delete => Op::Delete,
+            WriteKind::Lock => Op::Lock,
+            WriteKind::Rollback => {
+                panic!("Something wrong! A rollback never holds a lock");
+            }
+        }
+    }
+}
+
+impl<C: Clock> TimestampOracle<C> {
+    pub fn new(clock: C) -> TimestampOracle<C> {
+        TimestampOracle {
+            clock: Arc::new(clock),
+            state: Arc::new(Mutex::new(TsoState::default())),
+        }
+    }
+
+    // Opens the oracle persisted in `dir`. It resumes above every timestamp
+    // handed out before, whatever the clock says.
+    pub fn open<P: AsRef<Path>>(dir: P, clock: C) -> io::Result<TimestampOracle<C>> {
+        let dir = dir.as_ref().to_path_buf();
+        let limit = load_tso_limit(&dir)?;
+        Ok(TimestampOracle {
+            clock: Arc::new(clock),
+            state: Arc::new(Mutex::new(TsoState {
+                last_ts: limit,
+                limit,
+                dir: Some(dir),
+            })),
+        })
+    }
+}
+
+impl<C: Clock> Clone for TimestampOracle<C> {
+    fn clone(&self) -> Self {
+        TimestampOracle {
+            clock: self.clock.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<C: Clock> timestamp::Service for TimestampOracle<C> {
+    fn get_timestamp(&self, req: TimestampRequest) -> RpcFuture<TimestampResponse> {
+        let count = u64::from(req.count.max(1));
+        let mut state = self.state.lock().unwrap();
+        // Within a millisecond, or while the clock is behind the last
+        // timestamp, the logical counter moves the timestamp forward. Its
+        // overflow carries into the physical part.
+        let ts = TimeStamp::compose(self.clock.now_ms(), 0).max(state.last_ts + 1);
+        let last_ts = ts + (count - 1);
+        if last_ts >= state.limit {
+            // Persist a bound a window ahead, so that the timestamps up to
+            // it are handed out without touching the disk.
+            let limit = last_ts.add_ms(TSO_WINDOW_MS);
+            if let Some(ref dir) = state.dir {
+                if let Err(e) = save_tso_limit(dir, limit) {
+                    return Box::new(futures::future::result(Err(Error::Other(e.to_string()))));
                 }
-                return;
             }
+            state.limit = limit;
         }
+        state.last_ts = last_ts;
+        Box::new(futures::future::result(Ok(TimestampResponse {
+            ts: ts.into(),
+            not_leader: None,
+        })))
     }
 }
 
-impl timestamp::Service for TimestampOracle {
-    fn get_timestamp(&self, _: TimestampRequest) -> RpcFuture<TimestampResponse> {
-        let now = time::SystemTime::now();
-        let ts = TimestampResponse {
-            ts: now.duration_since(time::UNIX_EPOCH).expect("").as_nanos() as u64,
-        };
-        Box::new(futures::future::result(Ok(ts)))
+// Returns the limit stored in `dir`, creating it if needed, or zero if there
+// is none.
+pub fn load_tso_limit(dir: &Path) -> io::Result<TimeStamp> {
+    fs::create_dir_all(dir)?;
+    match fs::read(dir.join(TSO_LIMIT_FILE)) {
+        Ok(buf) => {
+            let mut limit = [0; 8];
+            if buf.len() != limit.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "corrupted timestamp limit",
+                ));
+            }
+            limit.copy_from_slice(&buf);
+            Ok(TimeStamp::from(u64::from_le_bytes(limit)))
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(TimeStamp::ZERO),
+        Err(e) => Err(e),
     }
 }
+
+pub fn save_tso_limit(dir: &Path, limit: TimeStamp) -> io::Result<()> {
+    let tmp = dir.join(TSO_LIMIT_TMP_FILE);
+    let mut file = File::create(&tmp)?;
+    file.write_all(&u64::from(limit).to_le_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, dir.join(TSO_LIMIT_FILE))?;
+    File::open(dir)?.sync_all()
+}
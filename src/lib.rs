@@ -4,41 +4,107 @@ extern crate labcodec;
 #[macro_use]
 extern crate labrpc;
 
+#[allow(dead_code)]
+mod async_client;
 #[allow(dead_code)]
 mod client;
+#[allow(dead_code)]
+mod config;
+#[allow(dead_code)]
+mod disk;
+#[allow(dead_code)]
+mod gc;
 mod imp;
 mod service;
 #[cfg(test)]
 mod tests;
+#[allow(dead_code)]
+mod tso_group;
 
 mod msg {
     include!(concat!(env!("OUT_DIR"), "/msg.rs"));
 }
 
+mod wal {
+    include!(concat!(env!("OUT_DIR"), "/wal.rs"));
+}
+
 use std::collections::BTreeMap;
+use std::io;
+use std::ops::{Add, Bound, Sub};
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
 use std::sync::{Arc, Mutex};
 use std::time;
 
-type Key = (Vec<u8>, u64);
+type Key = (Vec<u8>, TimeStamp);
 
+#[derive(Clone, Copy)]
 enum Column {
     Write,
     Data,
     Lock,
 }
 
+// The kind of a mutation, kept in its lock and then in its write record.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum WriteKind {
+    Put,
+    Delete,
+    // Locks the key without changing its value.
+    Lock,
+    // Marks a transaction rolled back, keyed at its start timestamp.
+    Rollback,
+}
+
+// The state of a transaction as recorded at its primary key.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TxnStatus {
+    // The primary is still locked, with the remaining TTL in milliseconds.
+    Locked(u64),
+    Committed(TimeStamp),
+    RolledBack,
+}
+
+// A lock holds a key for its transaction until the transaction is
+// committed or rolled back.
+#[derive(Clone, Debug, PartialEq)]
+struct Lock {
+    primary: Vec<u8>,
+    kind: WriteKind,
+    // The time to live in milliseconds from the start timestamp.
+    ttl: u64,
+    // The number of keys written by the transaction.
+    txn_size: u64,
+    // The smallest timestamp the transaction may commit at.
+    min_commit_ts: TimeStamp,
+    // The other keys of the transaction, only kept in the primary lock.
+    secondaries: Vec<Vec<u8>>,
+}
+
 #[derive(Clone, PartialEq)]
 enum Value {
-    Timestamp(u64),
+    // A write record points to the data written at the start timestamp.
+    Write(WriteKind, TimeStamp),
     Vector(Vec<u8>),
+    Lock(Lock),
 }
 
 impl Value {
-    fn unwrap_ts(self) -> u64 {
+    fn unwrap_write(self) -> (WriteKind, TimeStamp) {
+        match self {
+            Value::Write(kind, ts) => (kind, ts),
+            _ => {
+                panic!("Something wrong! It should be used for Write");
+            }
+        }
+    }
+
+    fn unwrap_lock(self) -> Lock {
         match self {
-            Value::Timestamp(ts) => ts,
+            Value::Lock(lock) => lock,
             _ => {
-                panic!("Something wrong! It should be used for Timestamp");
+                panic!("Something wrong! It should be used for Lock");
             }
         }
     }
@@ -53,6 +119,64 @@ impl Value {
     }
 }
 
+// Engine is the storage backend of the percolator table. It exposes the
+// per-column operations the transaction logic in `imp.rs` is built on.
+trait Engine: Send + 'static {
+    // Returns the newest version of `key` in the given timestamp range.
+    fn read(
+        &self,
+        key: Vec<u8>,
+        column: Column,
+        ts_start_inclusive: Option<TimeStamp>,
+        ts_end_inclusive: Option<TimeStamp>,
+    ) -> Option<(Key, Value)>;
+
+    fn write(&mut self, key: Vec<u8>, column: Column, ts: TimeStamp, value: Value);
+
+    // Removes every version of `key` at or below `commit_ts`.
+    fn erase(&mut self, key: Vec<u8>, column: Column, commit_ts: TimeStamp);
+
+    // Removes the version of `key` at exactly `ts`.
+    fn remove(&mut self, key: Vec<u8>, column: Column, ts: TimeStamp);
+
+    // Iterates the column in key order over the given bounds.
+    fn range<'a>(
+        &'a self,
+        column: Column,
+        start: Bound<Key>,
+        end: Bound<Key>,
+    ) -> Box<dyn DoubleEndedIterator<Item = (Key, Value)> + 'a>;
+
+    // Makes the mutations applied since the last sync durable as one unit.
+    // If it fails, the mutations are undone. Volatile engines have nothing
+    // to do here.
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Returns the locks left by the transaction at `ts`.
+    fn get_uncommitted_keys(&self, ts: TimeStamp) -> Vec<Key> {
+        self.range(Column::Lock, Bound::Unbounded, Bound::Unbounded)
+            .filter(|(map_key, _)| map_key.1 == ts)
+            .map(|(map_key, _)| map_key)
+            .collect()
+    }
+
+    fn get_commit_ts(&self, ts: TimeStamp, primary: Vec<u8>) -> Option<TimeStamp> {
+        self.range(
+            Column::Write,
+            Bound::Included((primary.clone(), TimeStamp::ZERO)),
+            Bound::Included((primary, TimeStamp::MAX)),
+        )
+        .find(|(_, v)| match v.clone().unwrap_write() {
+            (WriteKind::Rollback, _) => false,
+            (_, start_ts) => start_ts == ts,
+        })
+        .map(|(map_key, _)| map_key.1)
+    }
+}
+
+// KvTable is the default in-memory engine.
 #[derive(Clone, Default)]
 struct KvTable {
     write: BTreeMap<Key, Value>,
@@ -61,12 +185,122 @@ struct KvTable {
 }
 
 #[derive(Debug, Clone)]
-struct Write(Vec<u8>, Vec<u8>);
+struct Write(Vec<u8>, Vec<u8>, msg::Op);
 
-#[derive(Clone, Default)]
-struct MemoryStorage {
-    data: Arc<Mutex<KvTable>>,
+#[derive(Default)]
+struct MemoryStorage<E: Engine = KvTable> {
+    data: Arc<Mutex<E>>,
+    // Versions that no snapshot at or above the safe point can see are
+    // garbage collected.
+    safe_point: Arc<AtomicU64>,
 }
 
-#[derive(Clone, Default)]
-struct TimestampOracle {}
+const TSO_LOGICAL_BITS: u32 = 18;
+// The time in milliseconds the timestamp oracle may run ahead of the limit
+// it last stored.
+const TSO_WINDOW_MS: u64 = 3000;
+
+// TimeStamp holds the physical time in milliseconds since the Unix epoch
+// above a logical counter of TSO_LOGICAL_BITS bits. The messages carry its
+// raw u64, and adding to or subtracting from it moves the logical counter,
+// whose overflow carries into the physical part.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct TimeStamp(u64);
+
+impl TimeStamp {
+    const ZERO: TimeStamp = TimeStamp(0);
+    const MAX: TimeStamp = TimeStamp(u64::MAX);
+
+    fn compose(physical_ms: u64, logical: u64) -> TimeStamp {
+        TimeStamp((physical_ms << TSO_LOGICAL_BITS) | logical)
+    }
+
+    fn physical(self) -> u64 {
+        self.0 >> TSO_LOGICAL_BITS
+    }
+
+    fn logical(self) -> u64 {
+        self.0 & ((1 << TSO_LOGICAL_BITS) - 1)
+    }
+
+    // The first timestamp of the millisecond `time` falls in.
+    fn from_system_time(time: time::SystemTime) -> TimeStamp {
+        let since_epoch = time.duration_since(time::UNIX_EPOCH).expect("");
+        TimeStamp::compose(since_epoch.as_millis() as u64, 0)
+    }
+
+    fn to_system_time(self) -> time::SystemTime {
+        time::UNIX_EPOCH + time::Duration::from_millis(self.physical())
+    }
+
+    // The milliseconds of physical time from `earlier` to this timestamp, or
+    // zero if `earlier` is not earlier.
+    fn elapsed_ms_since(self, earlier: TimeStamp) -> u64 {
+        self.physical().saturating_sub(earlier.physical())
+    }
+
+    // The first timestamp `ms` milliseconds after this one.
+    fn add_ms(self, ms: u64) -> TimeStamp {
+        TimeStamp::compose(self.physical() + ms, 0)
+    }
+}
+
+impl From<u64> for TimeStamp {
+    fn from(ts: u64) -> TimeStamp {
+        TimeStamp(ts)
+    }
+}
+
+impl From<TimeStamp> for u64 {
+    fn from(ts: TimeStamp) -> u64 {
+        ts.0
+    }
+}
+
+impl Add<u64> for TimeStamp {
+    type Output = TimeStamp;
+
+    fn add(self, ticks: u64) -> TimeStamp {
+        TimeStamp(self.0 + ticks)
+    }
+}
+
+impl Sub<u64> for TimeStamp {
+    type Output = TimeStamp;
+
+    fn sub(self, ticks: u64) -> TimeStamp {
+        TimeStamp(self.0 - ticks)
+    }
+}
+
+// Clock is the physical time source of the timestamp oracle.
+trait Clock: Send + Sync + 'static {
+    // Returns the milliseconds since the Unix epoch.
+    fn now_ms(&self) -> u64;
+}
+
+#[derive(Default)]
+struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u64 {
+        let now = time::SystemTime::now();
+        now.duration_since(time::UNIX_EPOCH).expect("").as_millis() as u64
+    }
+}
+
+#[derive(Default)]
+struct TimestampOracle<C: Clock = SystemClock> {
+    clock: Arc<C>,
+    state: Arc<Mutex<TsoState>>,
+}
+
+#[derive(Default)]
+struct TsoState {
+    // The last timestamp handed out.
+    last_ts: TimeStamp,
+    // The timestamps below the limit can be handed out. A new limit is
+    // persisted in `dir`, if any, before any timestamp above the old one.
+    limit: TimeStamp,
+    dir: Option<PathBuf>,
+}
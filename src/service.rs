@@ -1,6 +1,9 @@
 use crate::msg::{
-    CommitRequest, CommitResponse, GetRequest, GetResponse, PrewriteRequest, PrewriteResponse,
-    TimestampRequest, TimestampResponse,
+    CheckTxnStatusRequest, CheckTxnStatusResponse, CommitRequest, CommitResponse, GcRequest,
+    GcResponse, GetRequest, GetResponse, HwmRequest, HwmResponse, PrewriteRequest,
+    PrewriteResponse, ResolveLockRequest, ResolveLockResponse, RollbackRequest, RollbackResponse,
+    ScanRequest, ScanResponse, TimestampRequest, TimestampResponse, TxnHeartBeatRequest,
+    TxnHeartBeatResponse, VoteRequest, VoteResponse,
 };
 
 service! {
@@ -11,11 +14,26 @@ service! {
 
 pub use timestamp::{add_service as add_tso_service, Client as TSOClient, Service};
 
+service! {
+    service tso_peer {
+        rpc request_vote(VoteRequest) returns (VoteResponse);
+        rpc append_hwm(HwmRequest) returns (HwmResponse);
+    }
+}
+
+pub use tso_peer::{add_service as add_tso_peer_service, Client as TsoPeerClient};
+
 service! {
     service transaction {
         rpc get(GetRequest) returns (GetResponse);
+        rpc scan(ScanRequest) returns (ScanResponse);
         rpc prewrite(PrewriteRequest) returns (PrewriteResponse);
         rpc commit(CommitRequest) returns (CommitResponse);
+        rpc rollback(RollbackRequest) returns (RollbackResponse);
+        rpc check_txn_status(CheckTxnStatusRequest) returns (CheckTxnStatusResponse);
+        rpc resolve_lock(ResolveLockRequest) returns (ResolveLockResponse);
+        rpc txn_heartbeat(TxnHeartBeatRequest) returns (TxnHeartBeatResponse);
+        rpc gc(GcRequest) returns (GcResponse);
     }
 }
 
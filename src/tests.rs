@@ -1,14 +1,39 @@
+use crate::async_client::AsyncClient;
 use crate::client::Client;
-use crate::service::{add_transaction_service, add_tso_service, TSOClient, TransactionClient};
-use crate::{MemoryStorage, TimestampOracle};
+use crate::config::{ClientConfig, RetryPolicy};
+use crate::disk::DiskEngine;
+use crate::msg::{
+    self, check_txn_status_response::Status, key_error, Aborted, CheckTxnStatusRequest,
+    CheckTxnStatusResponse, CommitRequest, CommitTsExpired, Committed, GcRequest, GetRequest, Op,
+    PrewriteRequest, ResolveLockRequest, RollbackRequest, ScanRequest, TimestampRequest,
+    TxnHeartBeatRequest, TxnNotFound, WriteConflict,
+};
+use crate::service::timestamp::Service as TimestampService;
+use crate::service::transaction::Service as TransactionService;
+use crate::service::{
+    add_transaction_service, add_tso_peer_service, add_tso_service, TSOClient, TransactionClient,
+    TsoPeerClient,
+};
+use crate::tso_group::{ElectionTimer, TsoReplica};
+use crate::{
+    Clock, Column, Engine, Key, KvTable, Lock, MemoryStorage, SystemClock, TimeStamp,
+    TimestampOracle, Value, WriteKind, TSO_WINDOW_MS,
+};
 
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::iter;
+use std::ops::Bound;
+use std::path::PathBuf;
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+    atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    Arc, Barrier,
 };
 use std::thread;
-use std::time::Duration;
+use std::time::{self, Duration, Instant};
 
+use futures::{future, Future};
 use labrpc::*;
 use prost::Message;
 
@@ -16,10 +41,30 @@ struct CommitHooks {
     drop_req: AtomicBool,
     drop_resp: AtomicBool,
     fail_primary: AtomicBool,
+    delay_primary_ms: AtomicU64,
+    delay_secondary_prewrite_ms: AtomicU64,
+    drop_heartbeat: AtomicBool,
 }
 
 impl RpcHooks for CommitHooks {
     fn before_dispatch(&self, fq_name: &str, req: &[u8]) -> Result<()> {
+        let delay = self.delay_primary_ms.load(Ordering::Relaxed);
+        if delay > 0 && fq_name == "transaction.commit" {
+            let m = crate::msg::CommitRequest::decode(req).unwrap();
+            if m.is_primary {
+                thread::sleep(Duration::from_millis(delay));
+            }
+        }
+        let delay = self.delay_secondary_prewrite_ms.load(Ordering::Relaxed);
+        if delay > 0 && fq_name == "transaction.prewrite" {
+            let m = crate::msg::PrewriteRequest::decode(req).unwrap();
+            if m.mutations.iter().all(|w| w.key != m.primary) {
+                thread::sleep(Duration::from_millis(delay));
+            }
+        }
+        if self.drop_heartbeat.load(Ordering::Relaxed) && fq_name == "transaction.txn_heartbeat" {
+            return Err(Error::Other("reqhook".to_owned()));
+        }
         if self.drop_req.load(Ordering::Relaxed) {
             if fq_name == "transaction.commit" {
                 let m = crate::msg::CommitRequest::decode(req).unwrap();
@@ -42,15 +87,31 @@ impl RpcHooks for CommitHooks {
 }
 
 fn init(num_clinet: usize) -> (Network, Vec<Client>, Arc<CommitHooks>) {
+    init_with_engine(num_clinet, KvTable::default())
+}
+
+fn init_with_engine<E: Engine>(
+    num_clinet: usize,
+    engine: E,
+) -> (Network, Vec<Client>, Arc<CommitHooks>) {
+    init_with_tso(num_clinet, engine, TimestampOracle::default())
+}
+
+// Sets up the servers on the given storage, the way they come back up after
+// a restart.
+fn init_with_tso<E: Engine>(
+    num_clinet: usize,
+    engine: E,
+    tso: TimestampOracle,
+) -> (Network, Vec<Client>, Arc<CommitHooks>) {
     let mut clients = vec![];
     let rn = Network::new();
     let tso_server_name = "tso_server";
     let mut tso_server_builder = ServerBuilder::new(tso_server_name.to_owned());
     let server_name = "server";
     let mut server_builder = ServerBuilder::new(server_name.to_owned());
-    let tso: TimestampOracle = Default::default();
     add_tso_service(tso, &mut tso_server_builder).unwrap();
-    let store: MemoryStorage = Default::default();
+    let store = MemoryStorage::new(engine);
     add_transaction_service(store, &mut server_builder).unwrap();
     let tso_server = tso_server_builder.build();
     let server = server_builder.build();
@@ -60,6 +121,9 @@ fn init(num_clinet: usize) -> (Network, Vec<Client>, Arc<CommitHooks>) {
         drop_req: AtomicBool::new(false),
         drop_resp: AtomicBool::new(false),
         fail_primary: AtomicBool::new(false),
+        delay_primary_ms: AtomicU64::new(0),
+        delay_secondary_prewrite_ms: AtomicU64::new(0),
+        drop_heartbeat: AtomicBool::new(false),
     });
     for i in 0..num_clinet {
         let txn_name_string = format!("txn{}", i);
@@ -81,6 +145,20 @@ fn init(num_clinet: usize) -> (Network, Vec<Client>, Arc<CommitHooks>) {
     (rn, clients, hook)
 }
 
+// Connects new clients named after `name` to the servers set up by init.
+fn connect(rn: &Network, name: &str) -> (TSOClient, TransactionClient) {
+    let tso_name = format!("{}_tso", name);
+    let cli = rn.create_client(tso_name.clone());
+    rn.enable(&tso_name, true);
+    rn.connect(&tso_name, "tso_server");
+    let tso_client = TSOClient::new(cli);
+    let txn_name = format!("{}_txn", name);
+    let cli = rn.create_client(txn_name.clone());
+    rn.enable(&txn_name, true);
+    rn.connect(&txn_name, "server");
+    (tso_client, TransactionClient::new(cli))
+}
+
 #[test]
 fn test_get_timestamp_under_unreliable_network() {
     let (rn, clients, _) = init(3);
@@ -117,22 +195,22 @@ fn test_get_timestamp_under_unreliable_network() {
 fn test_predicate_many_preceders_read_predicates() {
     let (_, clients, _) = init(3);
 
-    let mut client0 = clients[0].to_owned();
-    client0.begin();
-    client0.set(b"1".to_vec(), b"10".to_vec());
-    client0.set(b"2".to_vec(), b"20".to_vec());
-    assert_eq!(client0.commit(), Ok(true));
+    let client0 = clients[0].to_owned();
+    let mut txn0 = client0.begin().unwrap();
+    txn0.set(b"1".to_vec(), b"10".to_vec());
+    txn0.set(b"2".to_vec(), b"20".to_vec());
+    assert_eq!(txn0.commit(), Ok(true));
 
-    let mut client1 = clients[1].to_owned();
-    client1.begin();
-    assert_eq!(client1.get(b"3".to_vec()), Ok(Vec::new()));
+    let client1 = clients[1].to_owned();
+    let txn1 = client1.begin().unwrap();
+    assert_eq!(txn1.get(b"3".to_vec()), Ok(None));
 
-    let mut client2 = clients[2].to_owned();
-    client2.begin();
-    client2.set(b"3".to_vec(), b"30".to_vec());
-    assert_eq!(client2.commit(), Ok(true));
+    let client2 = clients[2].to_owned();
+    let mut txn2 = client2.begin().unwrap();
+    txn2.set(b"3".to_vec(), b"30".to_vec());
+    assert_eq!(txn2.commit(), Ok(true));
 
-    assert_eq!(client1.get(b"3".to_vec()), Ok(Vec::new()));
+    assert_eq!(txn1.get(b"3".to_vec()), Ok(None));
 }
 
 #[test]
@@ -140,25 +218,25 @@ fn test_predicate_many_preceders_read_predicates() {
 fn test_predicate_many_preceders_write_predicates() {
     let (_, clients, _) = init(3);
 
-    let mut client0 = clients[0].to_owned();
-    client0.begin();
-    client0.set(b"1".to_vec(), b"10".to_vec());
-    client0.set(b"2".to_vec(), b"20".to_vec());
-    assert_eq!(client0.commit(), Ok(true));
+    let client0 = clients[0].to_owned();
+    let mut txn0 = client0.begin().unwrap();
+    txn0.set(b"1".to_vec(), b"10".to_vec());
+    txn0.set(b"2".to_vec(), b"20".to_vec());
+    assert_eq!(txn0.commit(), Ok(true));
 
-    let mut client1 = clients[1].to_owned();
-    client1.begin();
+    let client1 = clients[1].to_owned();
+    let mut txn1 = client1.begin().unwrap();
 
-    let mut client2 = clients[2].to_owned();
-    client2.begin();
+    let client2 = clients[2].to_owned();
+    let mut txn2 = client2.begin().unwrap();
 
-    client1.set(b"1".to_vec(), b"20".to_vec());
-    client1.set(b"2".to_vec(), b"30".to_vec());
-    assert_eq!(client1.get(b"2".to_vec()), Ok(b"20".to_vec()));
+    txn1.set(b"1".to_vec(), b"20".to_vec());
+    txn1.set(b"2".to_vec(), b"30".to_vec());
+    assert_eq!(txn1.get(b"2".to_vec()), Ok(Some(b"30".to_vec())));
 
-    client2.set(b"2".to_vec(), b"40".to_vec());
-    assert_eq!(client1.commit(), Ok(true));
-    assert_eq!(client2.commit(), Ok(false));
+    txn2.set(b"2".to_vec(), b"40".to_vec());
+    assert_eq!(txn1.commit(), Ok(true));
+    assert_eq!(txn2.commit(), Ok(false));
 }
 
 #[test]
@@ -166,25 +244,25 @@ fn test_predicate_many_preceders_write_predicates() {
 fn test_lost_update() {
     let (_, clients, _) = init(3);
 
-    let mut client0 = clients[0].to_owned();
-    client0.begin();
-    client0.set(b"1".to_vec(), b"10".to_vec());
-    client0.set(b"2".to_vec(), b"20".to_vec());
-    assert_eq!(client0.commit(), Ok(true));
+    let client0 = clients[0].to_owned();
+    let mut txn0 = client0.begin().unwrap();
+    txn0.set(b"1".to_vec(), b"10".to_vec());
+    txn0.set(b"2".to_vec(), b"20".to_vec());
+    assert_eq!(txn0.commit(), Ok(true));
 
-    let mut client1 = clients[1].to_owned();
-    client1.begin();
+    let client1 = clients[1].to_owned();
+    let mut txn1 = client1.begin().unwrap();
 
-    let mut client2 = clients[2].to_owned();
-    client2.begin();
+    let client2 = clients[2].to_owned();
+    let mut txn2 = client2.begin().unwrap();
 
-    assert_eq!(client1.get(b"1".to_vec()), Ok(b"10".to_vec()));
-    assert_eq!(client2.get(b"1".to_vec()), Ok(b"10".to_vec()));
+    assert_eq!(txn1.get(b"1".to_vec()), Ok(Some(b"10".to_vec())));
+    assert_eq!(txn2.get(b"1".to_vec()), Ok(Some(b"10".to_vec())));
 
-    client1.set(b"1".to_vec(), b"11".to_vec());
-    client2.set(b"1".to_vec(), b"11".to_vec());
-    assert_eq!(client1.commit(), Ok(true));
-    assert_eq!(client2.commit(), Ok(false));
+    txn1.set(b"1".to_vec(), b"11".to_vec());
+    txn2.set(b"1".to_vec(), b"11".to_vec());
+    assert_eq!(txn1.commit(), Ok(true));
+    assert_eq!(txn2.commit(), Ok(false));
 }
 
 #[test]
@@ -192,27 +270,27 @@ fn test_lost_update() {
 fn test_read_skew_read_only() {
     let (_, clients, _) = init(3);
 
-    let mut client0 = clients[0].to_owned();
-    client0.begin();
-    client0.set(b"1".to_vec(), b"10".to_vec());
-    client0.set(b"2".to_vec(), b"20".to_vec());
-    assert_eq!(client0.commit(), Ok(true));
+    let client0 = clients[0].to_owned();
+    let mut txn0 = client0.begin().unwrap();
+    txn0.set(b"1".to_vec(), b"10".to_vec());
+    txn0.set(b"2".to_vec(), b"20".to_vec());
+    assert_eq!(txn0.commit(), Ok(true));
 
-    let mut client1 = clients[1].to_owned();
-    client1.begin();
+    let client1 = clients[1].to_owned();
+    let txn1 = client1.begin().unwrap();
 
-    let mut client2 = clients[2].to_owned();
-    client2.begin();
+    let client2 = clients[2].to_owned();
+    let mut txn2 = client2.begin().unwrap();
 
-    assert_eq!(client1.get(b"1".to_vec()), Ok(b"10".to_vec()));
-    assert_eq!(client2.get(b"1".to_vec()), Ok(b"10".to_vec()));
-    assert_eq!(client2.get(b"2".to_vec()), Ok(b"20".to_vec()));
+    assert_eq!(txn1.get(b"1".to_vec()), Ok(Some(b"10".to_vec())));
+    assert_eq!(txn2.get(b"1".to_vec()), Ok(Some(b"10".to_vec())));
+    assert_eq!(txn2.get(b"2".to_vec()), Ok(Some(b"20".to_vec())));
 
-    client2.set(b"1".to_vec(), b"12".to_vec());
-    client2.set(b"2".to_vec(), b"18".to_vec());
-    assert_eq!(client2.commit(), Ok(true));
+    txn2.set(b"1".to_vec(), b"12".to_vec());
+    txn2.set(b"2".to_vec(), b"18".to_vec());
+    assert_eq!(txn2.commit(), Ok(true));
 
-    assert_eq!(client1.get(b"2".to_vec()), Ok(b"20".to_vec()));
+    assert_eq!(txn1.get(b"2".to_vec()), Ok(Some(b"20".to_vec())));
 }
 
 #[test]
@@ -220,25 +298,25 @@ fn test_read_skew_read_only() {
 fn test_read_skew_predicate_dependencies() {
     let (_, clients, _) = init(3);
 
-    let mut client0 = clients[0].to_owned();
-    client0.begin();
-    client0.set(b"1".to_vec(), b"10".to_vec());
-    client0.set(b"2".to_vec(), b"20".to_vec());
-    assert_eq!(client0.commit(), Ok(true));
+    let client0 = clients[0].to_owned();
+    let mut txn0 = client0.begin().unwrap();
+    txn0.set(b"1".to_vec(), b"10".to_vec());
+    txn0.set(b"2".to_vec(), b"20".to_vec());
+    assert_eq!(txn0.commit(), Ok(true));
 
-    let mut client1 = clients[1].to_owned();
-    client1.begin();
+    let client1 = clients[1].to_owned();
+    let txn1 = client1.begin().unwrap();
 
-    let mut client2 = clients[2].to_owned();
-    client2.begin();
+    let client2 = clients[2].to_owned();
+    let mut txn2 = client2.begin().unwrap();
 
-    assert_eq!(client1.get(b"1".to_vec()), Ok(b"10".to_vec()));
-    assert_eq!(client1.get(b"2".to_vec()), Ok(b"20".to_vec()));
+    assert_eq!(txn1.get(b"1".to_vec()), Ok(Some(b"10".to_vec())));
+    assert_eq!(txn1.get(b"2".to_vec()), Ok(Some(b"20".to_vec())));
 
-    client2.set(b"3".to_vec(), b"30".to_vec());
-    assert_eq!(client2.commit(), Ok(true));
+    txn2.set(b"3".to_vec(), b"30".to_vec());
+    assert_eq!(txn2.commit(), Ok(true));
 
-    assert_eq!(client1.get(b"3".to_vec()), Ok(Vec::new()));
+    assert_eq!(txn1.get(b"3".to_vec()), Ok(None));
 }
 
 #[test]
@@ -246,28 +324,28 @@ fn test_read_skew_predicate_dependencies() {
 fn test_read_skew_write_predicate() {
     let (_, clients, _) = init(3);
 
-    let mut client0 = clients[0].to_owned();
-    client0.begin();
-    client0.set(b"1".to_vec(), b"10".to_vec());
-    client0.set(b"2".to_vec(), b"20".to_vec());
-    assert_eq!(client0.commit(), Ok(true));
+    let client0 = clients[0].to_owned();
+    let mut txn0 = client0.begin().unwrap();
+    txn0.set(b"1".to_vec(), b"10".to_vec());
+    txn0.set(b"2".to_vec(), b"20".to_vec());
+    assert_eq!(txn0.commit(), Ok(true));
 
-    let mut client1 = clients[1].to_owned();
-    client1.begin();
+    let client1 = clients[1].to_owned();
+    let mut txn1 = client1.begin().unwrap();
 
-    let mut client2 = clients[2].to_owned();
-    client2.begin();
+    let client2 = clients[2].to_owned();
+    let mut txn2 = client2.begin().unwrap();
 
-    assert_eq!(client1.get(b"1".to_vec()), Ok(b"10".to_vec()));
-    assert_eq!(client2.get(b"1".to_vec()), Ok(b"10".to_vec()));
-    assert_eq!(client2.get(b"2".to_vec()), Ok(b"20".to_vec()));
+    assert_eq!(txn1.get(b"1".to_vec()), Ok(Some(b"10".to_vec())));
+    assert_eq!(txn2.get(b"1".to_vec()), Ok(Some(b"10".to_vec())));
+    assert_eq!(txn2.get(b"2".to_vec()), Ok(Some(b"20".to_vec())));
 
-    client2.set(b"1".to_vec(), b"12".to_vec());
-    client2.set(b"2".to_vec(), b"18".to_vec());
-    assert_eq!(client2.commit(), Ok(true));
+    txn2.set(b"1".to_vec(), b"12".to_vec());
+    txn2.set(b"2".to_vec(), b"18".to_vec());
+    assert_eq!(txn2.commit(), Ok(true));
 
-    client1.set(b"2".to_vec(), b"30".to_vec());
-    assert_eq!(client1.commit(), Ok(false));
+    txn1.set(b"2".to_vec(), b"30".to_vec());
+    assert_eq!(txn1.commit(), Ok(false));
 }
 
 #[test]
@@ -275,28 +353,28 @@ fn test_read_skew_write_predicate() {
 fn test_write_skew() {
     let (_, clients, _) = init(3);
 
-    let mut client0 = clients[0].to_owned();
-    client0.begin();
-    client0.set(b"1".to_vec(), b"10".to_vec());
-    client0.set(b"2".to_vec(), b"20".to_vec());
-    assert_eq!(client0.commit(), Ok(true));
+    let client0 = clients[0].to_owned();
+    let mut txn0 = client0.begin().unwrap();
+    txn0.set(b"1".to_vec(), b"10".to_vec());
+    txn0.set(b"2".to_vec(), b"20".to_vec());
+    assert_eq!(txn0.commit(), Ok(true));
 
-    let mut client1 = clients[1].to_owned();
-    client1.begin();
+    let client1 = clients[1].to_owned();
+    let mut txn1 = client1.begin().unwrap();
 
-    let mut client2 = clients[2].to_owned();
-    client2.begin();
+    let client2 = clients[2].to_owned();
+    let mut txn2 = client2.begin().unwrap();
 
-    assert_eq!(client1.get(b"1".to_vec()), Ok(b"10".to_vec()));
-    assert_eq!(client1.get(b"2".to_vec()), Ok(b"20".to_vec()));
-    assert_eq!(client2.get(b"1".to_vec()), Ok(b"10".to_vec()));
-    assert_eq!(client2.get(b"2".to_vec()), Ok(b"20".to_vec()));
+    assert_eq!(txn1.get(b"1".to_vec()), Ok(Some(b"10".to_vec())));
+    assert_eq!(txn1.get(b"2".to_vec()), Ok(Some(b"20".to_vec())));
+    assert_eq!(txn2.get(b"1".to_vec()), Ok(Some(b"10".to_vec())));
+    assert_eq!(txn2.get(b"2".to_vec()), Ok(Some(b"20".to_vec())));
 
-    client1.set(b"1".to_vec(), b"11".to_vec());
-    client2.set(b"2".to_vec(), b"21".to_vec());
+    txn1.set(b"1".to_vec(), b"11".to_vec());
+    txn2.set(b"2".to_vec(), b"21".to_vec());
 
-    assert_eq!(client1.commit(), Ok(true));
-    assert_eq!(client2.commit(), Ok(true));
+    assert_eq!(txn1.commit(), Ok(true));
+    assert_eq!(txn2.commit(), Ok(true));
 }
 
 #[test]
@@ -304,103 +382,1793 @@ fn test_write_skew() {
 fn test_anti_dependency_cycles() {
     let (_, clients, _) = init(4);
 
-    let mut client0 = clients[0].to_owned();
-    client0.begin();
-    client0.set(b"1".to_vec(), b"10".to_vec());
-    client0.set(b"2".to_vec(), b"20".to_vec());
-    assert_eq!(client0.commit(), Ok(true));
+    let client0 = clients[0].to_owned();
+    let mut txn0 = client0.begin().unwrap();
+    txn0.set(b"1".to_vec(), b"10".to_vec());
+    txn0.set(b"2".to_vec(), b"20".to_vec());
+    assert_eq!(txn0.commit(), Ok(true));
 
-    let mut client1 = clients[1].to_owned();
-    client1.begin();
+    let client1 = clients[1].to_owned();
+    let mut txn1 = client1.begin().unwrap();
 
-    let mut client2 = clients[2].to_owned();
-    client2.begin();
+    let client2 = clients[2].to_owned();
+    let mut txn2 = client2.begin().unwrap();
 
-    client1.set(b"3".to_vec(), b"30".to_vec());
-    client2.set(b"4".to_vec(), b"42".to_vec());
+    txn1.set(b"3".to_vec(), b"30".to_vec());
+    txn2.set(b"4".to_vec(), b"42".to_vec());
 
-    assert_eq!(client1.commit(), Ok(true));
-    assert_eq!(client2.commit(), Ok(true));
+    assert_eq!(txn1.commit(), Ok(true));
+    assert_eq!(txn2.commit(), Ok(true));
 
-    let mut client3 = clients[3].to_owned();
-    client3.begin();
-    assert_eq!(client3.get(b"3".to_vec()), Ok(b"30".to_vec()));
-    assert_eq!(client3.get(b"4".to_vec()), Ok(b"42".to_vec()));
+    let client3 = clients[3].to_owned();
+    let txn3 = client3.begin().unwrap();
+    assert_eq!(txn3.get(b"3".to_vec()), Ok(Some(b"30".to_vec())));
+    assert_eq!(txn3.get(b"4".to_vec()), Ok(Some(b"42".to_vec())));
 }
 
 #[test]
 fn test_commit_primary_drop_secondary_requests() {
     let (_, clients, hook) = init(2);
 
-    let mut client0 = clients[0].to_owned();
-    client0.begin();
-    client0.set(b"3".to_vec(), b"30".to_vec());
-    client0.set(b"4".to_vec(), b"40".to_vec());
-    client0.set(b"5".to_vec(), b"50".to_vec());
+    let client0 = clients[0].to_owned();
+    let mut txn0 = client0.begin().unwrap();
+    txn0.set(b"3".to_vec(), b"30".to_vec());
+    txn0.set(b"4".to_vec(), b"40".to_vec());
+    txn0.set(b"5".to_vec(), b"50".to_vec());
     hook.drop_req.store(true, Ordering::Relaxed);
-    assert_eq!(client0.commit(), Ok(true));
+    assert_eq!(txn0.commit(), Ok(true));
 
-    let mut client1 = clients[1].to_owned();
-    client1.begin();
-    assert_eq!(client1.get(b"3".to_vec()).unwrap(), b"30");
-    assert_eq!(client1.get(b"4".to_vec()).unwrap(), b"40");
-    assert_eq!(client1.get(b"5".to_vec()).unwrap(), b"50");
+    let client1 = clients[1].to_owned();
+    let txn1 = client1.begin().unwrap();
+    assert_eq!(txn1.get(b"3".to_vec()).unwrap(), Some(b"30".to_vec()));
+    assert_eq!(txn1.get(b"4".to_vec()).unwrap(), Some(b"40".to_vec()));
+    assert_eq!(txn1.get(b"5".to_vec()).unwrap(), Some(b"50".to_vec()));
 }
 
 #[test]
 fn test_commit_primary_success() {
     let (_, clients, hook) = init(2);
 
-    let mut client0 = clients[0].to_owned();
-    client0.begin();
-    client0.set(b"3".to_vec(), b"30".to_vec());
-    client0.set(b"4".to_vec(), b"40".to_vec());
-    client0.set(b"5".to_vec(), b"50".to_vec());
+    let client0 = clients[0].to_owned();
+    let mut txn0 = client0.begin().unwrap();
+    txn0.set(b"3".to_vec(), b"30".to_vec());
+    txn0.set(b"4".to_vec(), b"40".to_vec());
+    txn0.set(b"5".to_vec(), b"50".to_vec());
     hook.drop_req.store(true, Ordering::Relaxed);
-    assert_eq!(client0.commit(), Ok(true));
+    assert_eq!(txn0.commit(), Ok(true));
 
-    let mut client1 = clients[1].to_owned();
-    client1.begin();
-    assert_eq!(client1.get(b"3".to_vec()).unwrap(), b"30");
-    assert_eq!(client1.get(b"4".to_vec()).unwrap(), b"40");
-    assert_eq!(client1.get(b"5".to_vec()).unwrap(), b"50");
+    let client1 = clients[1].to_owned();
+    let txn1 = client1.begin().unwrap();
+    assert_eq!(txn1.get(b"3".to_vec()).unwrap(), Some(b"30".to_vec()));
+    assert_eq!(txn1.get(b"4".to_vec()).unwrap(), Some(b"40".to_vec()));
+    assert_eq!(txn1.get(b"5".to_vec()).unwrap(), Some(b"50".to_vec()));
 }
 
 #[test]
 fn test_commit_primary_success_without_response() {
     let (_, clients, hook) = init(2);
 
-    let mut client0 = clients[0].to_owned();
-    client0.begin();
-    client0.set(b"3".to_vec(), b"30".to_vec());
-    client0.set(b"4".to_vec(), b"40".to_vec());
-    client0.set(b"5".to_vec(), b"50".to_vec());
+    let client0 = clients[0].to_owned();
+    let mut txn0 = client0.begin().unwrap();
+    txn0.set(b"3".to_vec(), b"30".to_vec());
+    txn0.set(b"4".to_vec(), b"40".to_vec());
+    txn0.set(b"5".to_vec(), b"50".to_vec());
     hook.drop_resp.store(true, Ordering::Relaxed);
-    assert_eq!(client0.commit(), Err(Error::Other("resphook".to_owned())));
+    assert_eq!(txn0.commit(), Err(Error::Other("resphook".to_owned())));
 
-    let mut client1 = clients[1].to_owned();
-    client1.begin();
-    assert_eq!(client1.get(b"3".to_vec()).unwrap(), b"30");
-    assert_eq!(client1.get(b"4".to_vec()).unwrap(), b"40");
-    assert_eq!(client1.get(b"5".to_vec()).unwrap(), b"50");
+    let client1 = clients[1].to_owned();
+    let txn1 = client1.begin().unwrap();
+    assert_eq!(txn1.get(b"3".to_vec()).unwrap(), Some(b"30".to_vec()));
+    assert_eq!(txn1.get(b"4".to_vec()).unwrap(), Some(b"40".to_vec()));
+    assert_eq!(txn1.get(b"5".to_vec()).unwrap(), Some(b"50".to_vec()));
 }
 
 #[test]
 fn test_commit_primary_fail() {
     let (_, clients, hook) = init(2);
 
-    let mut client0 = clients[0].to_owned();
-    client0.begin();
-    client0.set(b"3".to_vec(), b"30".to_vec());
-    client0.set(b"4".to_vec(), b"40".to_vec());
-    client0.set(b"5".to_vec(), b"50".to_vec());
+    let client0 = clients[0].to_owned();
+    let mut txn0 = client0.begin().unwrap();
+    txn0.set(b"3".to_vec(), b"30".to_vec());
+    txn0.set(b"4".to_vec(), b"40".to_vec());
+    txn0.set(b"5".to_vec(), b"50".to_vec());
     hook.drop_req.store(true, Ordering::Relaxed);
     hook.fail_primary.store(true, Ordering::Relaxed);
-    assert_eq!(client0.commit(), Ok(false));
+    assert_eq!(txn0.commit(), Ok(false));
+
+    let client1 = clients[1].to_owned();
+    let txn1 = client1.begin().unwrap();
+    assert_eq!(txn1.get(b"3".to_vec()), Ok(None));
+    assert_eq!(txn1.get(b"4".to_vec()), Ok(None));
+    assert_eq!(txn1.get(b"5".to_vec()), Ok(None));
+}
+
+struct CountingEngine {
+    inner: KvTable,
+    writes: Arc<AtomicUsize>,
+}
+
+impl Engine for CountingEngine {
+    fn read(
+        &self,
+        key: Vec<u8>,
+        column: Column,
+        ts_start_inclusive: Option<TimeStamp>,
+        ts_end_inclusive: Option<TimeStamp>,
+    ) -> Option<(Key, Value)> {
+        self.inner
+            .read(key, column, ts_start_inclusive, ts_end_inclusive)
+    }
+
+    fn write(&mut self, key: Vec<u8>, column: Column, ts: TimeStamp, value: Value) {
+        self.writes.fetch_add(1, Ordering::SeqCst);
+        self.inner.write(key, column, ts, value)
+    }
+
+    fn erase(&mut self, key: Vec<u8>, column: Column, commit_ts: TimeStamp) {
+        self.inner.erase(key, column, commit_ts)
+    }
+
+    fn remove(&mut self, key: Vec<u8>, column: Column, ts: TimeStamp) {
+        self.inner.remove(key, column, ts)
+    }
+
+    fn range<'a>(
+        &'a self,
+        column: Column,
+        start: Bound<Key>,
+        end: Bound<Key>,
+    ) -> Box<dyn DoubleEndedIterator<Item = (Key, Value)> + 'a> {
+        self.inner.range(column, start, end)
+    }
+}
+
+#[test]
+fn test_custom_engine() {
+    let writes = Arc::new(AtomicUsize::new(0));
+    let engine = CountingEngine {
+        inner: KvTable::default(),
+        writes: writes.clone(),
+    };
+    let (_, clients, _) = init_with_engine(2, engine);
+
+    let client0 = clients[0].to_owned();
+    let mut txn0 = client0.begin().unwrap();
+    txn0.set(b"1".to_vec(), b"10".to_vec());
+    txn0.set(b"2".to_vec(), b"20".to_vec());
+    assert_eq!(txn0.commit(), Ok(true));
+    assert!(writes.load(Ordering::SeqCst) > 0);
+
+    let client1 = clients[1].to_owned();
+    let txn1 = client1.begin().unwrap();
+    assert_eq!(txn1.get(b"1".to_vec()), Ok(Some(b"10".to_vec())));
+    assert_eq!(txn1.get(b"2".to_vec()), Ok(Some(b"20".to_vec())));
+}
+
+fn open_tso(dir: &PathBuf) -> TimestampOracle {
+    TimestampOracle::open(dir, SystemClock).unwrap()
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("percolator-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+#[test]
+fn test_disk_engine_recover_after_crash_in_commit() {
+    let dir = temp_dir("crash-in-commit");
+    {
+        let (_, clients, hook) = init_with_tso(1, DiskEngine::open(&dir).unwrap(), open_tso(&dir));
+
+        let client0 = clients[0].to_owned();
+        let mut txn0 = client0.begin().unwrap();
+        txn0.set(b"1".to_vec(), b"10".to_vec());
+        txn0.set(b"2".to_vec(), b"20".to_vec());
+        assert_eq!(txn0.commit(), Ok(true));
+
+        // The server dies after the primary is committed.
+        let mut txn0 = client0.begin().unwrap();
+        txn0.set(b"3".to_vec(), b"30".to_vec());
+        txn0.set(b"4".to_vec(), b"40".to_vec());
+        txn0.set(b"5".to_vec(), b"50".to_vec());
+        hook.drop_req.store(true, Ordering::Relaxed);
+        assert_eq!(txn0.commit(), Ok(true));
+
+        // The server dies before the primary is committed.
+        let mut txn0 = client0.begin().unwrap();
+        txn0.set(b"6".to_vec(), b"60".to_vec());
+        txn0.set(b"7".to_vec(), b"70".to_vec());
+        hook.fail_primary.store(true, Ordering::Relaxed);
+        assert_eq!(txn0.commit(), Ok(false));
+    }
+
+    let (_, clients, _) = init_with_tso(1, DiskEngine::open(&dir).unwrap(), open_tso(&dir));
+    let client1 = clients[0].to_owned();
+    let txn1 = client1.begin().unwrap();
+    assert_eq!(txn1.get(b"1".to_vec()), Ok(Some(b"10".to_vec())));
+    assert_eq!(txn1.get(b"2".to_vec()), Ok(Some(b"20".to_vec())));
+    assert_eq!(txn1.get(b"3".to_vec()), Ok(Some(b"30".to_vec())));
+    assert_eq!(txn1.get(b"4".to_vec()), Ok(Some(b"40".to_vec())));
+    assert_eq!(txn1.get(b"5".to_vec()), Ok(Some(b"50".to_vec())));
+    assert_eq!(txn1.get(b"6".to_vec()), Ok(None));
+    assert_eq!(txn1.get(b"7".to_vec()), Ok(None));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_disk_engine_checkpoint_and_torn_wal() {
+    let dir = temp_dir("checkpoint-torn-wal");
+    let open = |dir: &PathBuf| {
+        let mut engine = DiskEngine::open(dir).unwrap();
+        engine.set_checkpoint_interval(3);
+        engine
+    };
+    {
+        let (_, clients, _) = init_with_tso(1, open(&dir), open_tso(&dir));
+        let client0 = clients[0].to_owned();
+        for i in 0..5u8 {
+            let mut txn0 = client0.begin().unwrap();
+            txn0.set(vec![i], vec![i, i]);
+            assert_eq!(txn0.commit(), Ok(true));
+        }
+    }
+
+    // A crash in the middle of an append leaves a partial record behind.
+    let mut wal = OpenOptions::new()
+        .append(true)
+        .open(dir.join("wal"))
+        .unwrap();
+    wal.write_all(&[42, 0, 0, 0, 1, 2]).unwrap();
+
+    {
+        let (_, clients, _) = init_with_tso(1, open(&dir), open_tso(&dir));
+        let client0 = clients[0].to_owned();
+        let mut txn0 = client0.begin().unwrap();
+        for i in 0..5u8 {
+            assert_eq!(txn0.get(vec![i]), Ok(Some(vec![i, i])));
+        }
+        txn0.set(vec![5], vec![5, 5]);
+        assert_eq!(txn0.commit(), Ok(true));
+    }
+
+    let (_, clients, _) = init_with_tso(1, open(&dir), open_tso(&dir));
+    let client0 = clients[0].to_owned();
+    let txn0 = client0.begin().unwrap();
+    for i in 0..6u8 {
+        assert_eq!(txn0.get(vec![i]), Ok(Some(vec![i, i])));
+    }
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_disk_engine_corrupted_wal() {
+    let dir = temp_dir("corrupted-wal");
+    {
+        let (_, clients, _) = init_with_tso(1, DiskEngine::open(&dir).unwrap(), open_tso(&dir));
+        let client0 = clients[0].to_owned();
+        for i in 0..3u8 {
+            let mut txn0 = client0.begin().unwrap();
+            txn0.set(vec![i], vec![i, i]);
+            assert_eq!(txn0.commit(), Ok(true));
+        }
+    }
+
+    // A flipped bit in the first record fails the whole log instead of
+    // dropping the records after it.
+    let mut buf = fs::read(dir.join("wal")).unwrap();
+    buf[12] ^= 1;
+    fs::write(dir.join("wal"), &buf).unwrap();
+    let err = DiskEngine::open(&dir).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(fs::read(dir.join("wal")).unwrap(), buf);
+
+    let _ = fs::remove_dir_all(&dir);
+}
 
-    let mut client1 = clients[1].to_owned();
-    client1.begin();
-    assert_eq!(client1.get(b"3".to_vec()), Ok(Vec::new()));
-    assert_eq!(client1.get(b"4".to_vec()), Ok(Vec::new()));
-    assert_eq!(client1.get(b"5".to_vec()), Ok(Vec::new()));
+fn must_prewrite(store: &MemoryStorage, key: &[u8], value: &[u8], op: Op, start_ts: u64) {
+    let write = msg::Write {
+        key: key.to_vec(),
+        value: value.to_vec(),
+        op: op as i32,
+    };
+    let resp = store
+        .prewrite(PrewriteRequest {
+            start_ts,
+            primary: write.key.clone(),
+            mutations: vec![write],
+            ..Default::default()
+        })
+        .wait()
+        .unwrap();
+    assert_eq!(resp.error, None);
+}
+
+fn must_commit_key(store: &MemoryStorage, key: &[u8], start_ts: u64, commit_ts: u64) {
+    store
+        .commit(CommitRequest {
+            is_primary: true,
+            start_ts,
+            commit_ts,
+            keys: vec![key.to_vec()],
+        })
+        .wait()
+        .unwrap();
+}
+
+fn must_commit(store: &MemoryStorage, key: &[u8], value: &[u8], start_ts: u64, commit_ts: u64) {
+    must_prewrite(store, key, value, Op::Put, start_ts);
+    must_commit_key(store, key, start_ts, commit_ts);
+}
+
+fn must_get(store: &MemoryStorage, key: &[u8], start_ts: u64) -> Vec<u8> {
+    let resp = store
+        .get(GetRequest {
+            start_ts,
+            key: key.to_vec(),
+        })
+        .wait()
+        .unwrap();
+    assert_eq!(resp.error, None);
+    resp.value
+}
+
+fn write_versions(store: &MemoryStorage, key: &[u8]) -> usize {
+    store
+        .data
+        .lock()
+        .unwrap()
+        .range(
+            Column::Write,
+            Bound::Included((key.to_vec(), TimeStamp::ZERO)),
+            Bound::Included((key.to_vec(), TimeStamp::MAX)),
+        )
+        .count()
+}
+
+#[test]
+fn test_gc() {
+    let store = MemoryStorage::default();
+    must_commit(&store, b"1", b"10", 10, 11);
+    must_commit(&store, b"1", b"11", 20, 21);
+    must_commit(&store, b"1", b"12", 30, 31);
+    must_commit(&store, b"1", b"13", 50, 51);
+    must_commit(&store, b"2", b"20", 12, 13);
+    must_commit(&store, b"2", b"21", 22, 23);
+    must_prewrite(&store, b"2", b"22", Op::Put, 32);
+
+    let resp = store.gc(GcRequest { safe_point: 40 }).wait().unwrap();
+    assert_eq!(resp.reclaimed, 2);
+    assert_eq!(write_versions(&store, b"1"), 2);
+    assert_eq!(write_versions(&store, b"2"), 2);
+    assert_eq!(must_get(&store, b"1", 40), b"12");
+    assert_eq!(must_get(&store, b"1", 60), b"13");
+
+    // The safe point never moves backwards.
+    let resp = store.gc(GcRequest { safe_point: 20 }).wait().unwrap();
+    assert_eq!(resp.reclaimed, 0);
+
+    // Once the lock is released the background worker reclaims the key.
+    must_commit_key(&store, b"2", 32, 33);
+    let _worker = store.start_gc_worker(Duration::from_millis(10));
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(write_versions(&store, b"2"), 1);
+    assert_eq!(must_get(&store, b"2", 40), b"22");
+}
+
+#[test]
+fn test_scan() {
+    let (_, clients, _) = init(3);
+
+    let client0 = clients[0].to_owned();
+    let mut txn0 = client0.begin().unwrap();
+    for i in 0..100u8 {
+        txn0.set(vec![i], vec![i]);
+    }
+    assert_eq!(txn0.commit(), Ok(true));
+
+    let client1 = clients[1].to_owned();
+    let txn1 = client1.begin().unwrap();
+
+    let client2 = clients[2].to_owned();
+    let mut txn2 = client2.begin().unwrap();
+    txn2.set(vec![0], vec![1]);
+    txn2.set(vec![100], vec![100]);
+    assert_eq!(txn2.commit(), Ok(true));
+
+    let pairs = |r: Vec<u8>| {
+        r.into_iter()
+            .map(|i| (vec![i], vec![i]))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        txn1.scan(vec![], vec![], 0, false),
+        Ok(pairs((0..100).collect()))
+    );
+    assert_eq!(
+        txn1.scan(vec![10], vec![90], 0, true),
+        Ok(pairs((10..90).rev().collect()))
+    );
+    assert_eq!(
+        txn1.scan(vec![10], vec![], 70, false),
+        Ok(pairs((10..80).collect()))
+    );
+    assert_eq!(
+        txn1.scan(vec![], vec![], 70, true),
+        Ok(pairs((30..100).rev().collect()))
+    );
+}
+
+#[test]
+fn test_scan_empty_range() {
+    let store = MemoryStorage::default();
+    must_commit(&store, b"1", b"10", 1, 2);
+    must_commit(&store, b"3", b"30", 3, 4);
+    for (start_key, end_key) in &[(b"3", b"1"), (b"2", b"2")] {
+        for &reverse in &[false, true] {
+            let resp = store
+                .scan(ScanRequest {
+                    start_ts: 10,
+                    start_key: start_key.to_vec(),
+                    end_key: end_key.to_vec(),
+                    limit: 0,
+                    reverse,
+                })
+                .wait()
+                .unwrap();
+            assert_eq!(resp.error, None);
+            assert!(resp.pairs.is_empty());
+        }
+    }
+    // The store keeps serving requests.
+    assert_eq!(must_get(&store, b"3", 10), b"30");
+}
+
+#[test]
+fn test_reverse_scan_to_empty_key() {
+    let (_, clients, _) = init(1);
+
+    let client0 = clients[0].to_owned();
+    let mut txn0 = client0.begin().unwrap();
+    txn0.set(vec![], vec![0]);
+    for i in 0..63u8 {
+        txn0.set(vec![i], vec![i]);
+    }
+    assert_eq!(txn0.commit(), Ok(true));
+
+    let txn1 = client0.begin().unwrap();
+    let mut expected: Vec<_> = (0..63u8).rev().map(|i| (vec![i], vec![i])).collect();
+    expected.push((vec![], vec![0]));
+    assert_eq!(txn1.scan(vec![], vec![], 0, true), Ok(expected));
+}
+
+#[test]
+fn test_delete() {
+    let (_, clients, _) = init(2);
+
+    let client0 = clients[0].to_owned();
+    let mut txn0 = client0.begin().unwrap();
+    txn0.set(b"1".to_vec(), b"10".to_vec());
+    txn0.set(b"2".to_vec(), b"20".to_vec());
+    txn0.set(b"3".to_vec(), b"30".to_vec());
+    assert_eq!(txn0.commit(), Ok(true));
+
+    let mut txn0 = client0.begin().unwrap();
+    txn0.delete(b"1".to_vec());
+    txn0.set(b"3".to_vec(), Vec::new());
+    assert_eq!(txn0.commit(), Ok(true));
+
+    let client1 = clients[1].to_owned();
+    let txn1 = client1.begin().unwrap();
+    assert_eq!(txn1.get(b"1".to_vec()), Ok(None));
+    // An empty value is still a value.
+    assert_eq!(txn1.get(b"3".to_vec()), Ok(Some(Vec::new())));
+    assert_eq!(
+        txn1.scan(vec![], vec![], 0, false),
+        Ok(vec![
+            (b"2".to_vec(), b"20".to_vec()),
+            (b"3".to_vec(), Vec::new())
+        ])
+    );
+}
+
+#[test]
+fn test_lock_only_mutation() {
+    let store = MemoryStorage::default();
+    must_commit(&store, b"1", b"10", 10, 11);
+    must_prewrite(&store, b"1", b"", Op::Lock, 20);
+    must_commit_key(&store, b"1", 20, 21);
+    must_prewrite(&store, b"1", b"", Op::Delete, 30);
+    must_commit_key(&store, b"1", 30, 31);
+
+    assert_eq!(must_get(&store, b"1", 25), b"10");
+    assert_eq!(must_get(&store, b"1", 35), b"");
+
+    // GC drops the delete together with every version below it.
+    let resp = store.gc(GcRequest { safe_point: 40 }).wait().unwrap();
+    assert_eq!(resp.reclaimed, 3);
+    assert_eq!(write_versions(&store, b"1"), 0);
+}
+
+#[test]
+fn test_rollback_on_prewrite_failure() {
+    let (_, clients, _) = init(3);
+
+    let client0 = clients[0].to_owned();
+    let mut txn0 = client0.begin().unwrap();
+
+    let client1 = clients[1].to_owned();
+    let mut txn1 = client1.begin().unwrap();
+    txn1.set(b"2".to_vec(), b"20".to_vec());
+    assert_eq!(txn1.commit(), Ok(true));
+
+    // The primary is locked before the secondary hits the conflict.
+    txn0.set(b"1".to_vec(), b"10".to_vec());
+    txn0.set(b"2".to_vec(), b"21".to_vec());
+    assert_eq!(txn0.commit(), Ok(false));
+
+    // The lock on the primary has been released by the rollback.
+    let client2 = clients[2].to_owned();
+    let mut txn2 = client2.begin().unwrap();
+    txn2.set(b"1".to_vec(), b"11".to_vec());
+    assert_eq!(txn2.commit(), Ok(true));
+}
+
+#[test]
+fn test_rollback() {
+    let (_, clients, _) = init(1);
+
+    let client0 = clients[0].to_owned();
+    let mut txn0 = client0.begin().unwrap();
+    txn0.set(b"1".to_vec(), b"10".to_vec());
+    txn0.set(b"2".to_vec(), b"20".to_vec());
+    assert_eq!(txn0.rollback(), Ok(()));
+
+    let txn0 = client0.begin().unwrap();
+    assert_eq!(txn0.get(b"1".to_vec()), Ok(None));
+    assert_eq!(txn0.get(b"2".to_vec()), Ok(None));
+
+    let store = MemoryStorage::default();
+    must_commit(&store, b"1", b"10", 10, 11);
+    must_prewrite(&store, b"1", b"12", Op::Put, 20);
+    store
+        .rollback(RollbackRequest {
+            start_ts: 20,
+            keys: vec![b"1".to_vec()],
+        })
+        .wait()
+        .unwrap();
+    assert_eq!(must_get(&store, b"1", 30), b"10");
+
+    // A delayed prewrite of the rolled back transaction fails.
+    let write = msg::Write {
+        key: b"1".to_vec(),
+        value: b"12".to_vec(),
+        op: Op::Put as i32,
+    };
+    let resp = store
+        .prewrite(PrewriteRequest {
+            start_ts: 20,
+            primary: write.key.clone(),
+            mutations: vec![write],
+            ..Default::default()
+        })
+        .wait()
+        .unwrap();
+    assert_eq!(
+        resp.error.unwrap().error,
+        Some(key_error::Error::Aborted(Aborted { start_ts: 20 }))
+    );
+
+    // A committed transaction cannot be rolled back.
+    let resp = store
+        .rollback(RollbackRequest {
+            start_ts: 10,
+            keys: vec![b"1".to_vec()],
+        })
+        .wait()
+        .unwrap();
+    assert_eq!(
+        resp.error.unwrap().error,
+        Some(key_error::Error::Committed(Committed { commit_ts: 11 }))
+    );
+    assert_eq!(must_get(&store, b"1", 30), b"10");
+}
+
+#[test]
+fn test_delayed_commit_after_rollback() {
+    let (_, clients, hook) = init(3);
+
+    let client0 = clients[0].to_owned();
+    let mut txn0 = client0.begin().unwrap();
+    txn0.set(b"3".to_vec(), b"30".to_vec());
+    txn0.set(b"4".to_vec(), b"40".to_vec());
+    // The primary commit is delivered after a reader rolls back the
+    // transaction, which expired without heartbeats.
+    hook.delay_primary_ms.store(500, Ordering::Relaxed);
+    hook.drop_heartbeat.store(true, Ordering::Relaxed);
+    let child = thread::spawn(move || txn0.commit());
+
+    thread::sleep(Duration::from_millis(200));
+    let client1 = clients[1].to_owned();
+    let txn1 = client1.begin().unwrap();
+    assert_eq!(txn1.get(b"3".to_vec()), Ok(None));
+
+    assert_eq!(child.join().unwrap(), Ok(false));
+    hook.delay_primary_ms.store(0, Ordering::Relaxed);
+    hook.drop_heartbeat.store(false, Ordering::Relaxed);
+
+    let client2 = clients[2].to_owned();
+    let txn2 = client2.begin().unwrap();
+    assert_eq!(txn2.get(b"3".to_vec()), Ok(None));
+    assert_eq!(txn2.get(b"4".to_vec()), Ok(None));
+}
+
+#[test]
+fn test_heartbeat_during_primary_commit() {
+    let (_, clients, hook) = init(3);
+
+    let client0 = clients[0].to_owned();
+    let mut txn0 = client0.begin().unwrap();
+    txn0.set(b"3".to_vec(), b"30".to_vec());
+    txn0.set(b"4".to_vec(), b"40".to_vec());
+    // The heartbeats keep the primary alive past its TTL while the commit
+    // is delayed, so a reader cannot roll it back.
+    hook.delay_primary_ms.store(500, Ordering::Relaxed);
+    let child = thread::spawn(move || txn0.commit());
+
+    thread::sleep(Duration::from_millis(200));
+    let client1 = clients[1].to_owned();
+    let txn1 = client1.begin().unwrap();
+    assert_eq!(txn1.get(b"3".to_vec()), Ok(None));
+
+    assert_eq!(child.join().unwrap(), Ok(true));
+    hook.delay_primary_ms.store(0, Ordering::Relaxed);
+
+    let client2 = clients[2].to_owned();
+    let txn2 = client2.begin().unwrap();
+    assert_eq!(txn2.get(b"3".to_vec()), Ok(Some(b"30".to_vec())));
+    assert_eq!(txn2.get(b"4".to_vec()), Ok(Some(b"40".to_vec())));
+}
+
+#[test]
+fn test_rollback_protection() {
+    let store = MemoryStorage::default();
+    must_prewrite(&store, b"1", b"10", Op::Put, 1);
+    let resp = store
+        .get(GetRequest {
+            start_ts: 2,
+            key: b"1".to_vec(),
+        })
+        .wait()
+        .unwrap();
+    match resp.error.unwrap().error {
+        Some(key_error::Error::Locked(lock)) => assert_eq!(lock.primary, b"1"),
+        e => panic!("unexpected error {:?}", e),
+    }
+    // The lock has long expired, so the transaction is rolled back.
+    let resp = must_check_txn_status(&store, b"1", 1, (TimeStamp::compose(1000, 0) + 2).into());
+    assert_eq!(resp.status, Status::RolledBack as i32);
+    assert_eq!(must_get(&store, b"1", 2), b"");
+
+    let write = msg::Write {
+        key: b"1".to_vec(),
+        value: b"10".to_vec(),
+        op: Op::Put as i32,
+    };
+    let resp = store
+        .commit(CommitRequest {
+            is_primary: true,
+            start_ts: 1,
+            commit_ts: 3,
+            keys: vec![write.key.clone()],
+        })
+        .wait()
+        .unwrap();
+    assert_eq!(
+        resp.error.unwrap().error,
+        Some(key_error::Error::Aborted(Aborted { start_ts: 1 }))
+    );
+    let resp = store
+        .prewrite(PrewriteRequest {
+            start_ts: 1,
+            primary: write.key.clone(),
+            mutations: vec![write],
+            ..Default::default()
+        })
+        .wait()
+        .unwrap();
+    assert_eq!(
+        resp.error.unwrap().error,
+        Some(key_error::Error::Aborted(Aborted { start_ts: 1 }))
+    );
+    assert_eq!(must_get(&store, b"1", 4), b"");
+}
+
+fn must_check_txn_status(
+    store: &MemoryStorage,
+    primary: &[u8],
+    lock_ts: u64,
+    current_ts: u64,
+) -> CheckTxnStatusResponse {
+    store
+        .check_txn_status(CheckTxnStatusRequest {
+            primary_key: primary.to_vec(),
+            lock_ts,
+            caller_start_ts: current_ts,
+            current_ts,
+        })
+        .wait()
+        .unwrap()
+}
+
+#[test]
+fn test_check_txn_status() {
+    let store = MemoryStorage::default();
+    let ms = u64::from(TimeStamp::compose(1, 0));
+
+    must_prewrite(&store, b"1", b"10", Op::Put, 1000 * ms);
+    let resp = must_check_txn_status(&store, b"1", 1000 * ms, 1040 * ms);
+    assert_eq!(resp.status, Status::Locked as i32);
+    assert_eq!(resp.lock_ttl, 60);
+    let resp = must_check_txn_status(&store, b"1", 1000 * ms, 1040 * ms + 1);
+    assert_eq!(resp.lock_ttl, 60);
+
+    // The expired primary is rolled back as a side effect.
+    let resp = must_check_txn_status(&store, b"1", 1000 * ms, 1200 * ms);
+    assert_eq!(resp.status, Status::RolledBack as i32);
+    let resp = must_check_txn_status(&store, b"1", 1000 * ms, 1040 * ms);
+    assert_eq!(resp.status, Status::RolledBack as i32);
+
+    must_commit(&store, b"2", b"20", 1100 * ms, 1150 * ms);
+    let resp = must_check_txn_status(&store, b"2", 1100 * ms, 1200 * ms);
+    assert_eq!(resp.status, Status::Committed as i32);
+    assert_eq!(resp.commit_ts, 1150 * ms);
+
+    // A primary that was never locked cannot be prewritten later on.
+    let resp = must_check_txn_status(&store, b"3", 1300 * ms, 1300 * ms);
+    assert_eq!(resp.status, Status::RolledBack as i32);
+    let write = msg::Write {
+        key: b"3".to_vec(),
+        value: b"30".to_vec(),
+        op: Op::Put as i32,
+    };
+    let resp = store
+        .prewrite(PrewriteRequest {
+            start_ts: 1300 * ms,
+            primary: write.key.clone(),
+            mutations: vec![write],
+            ..Default::default()
+        })
+        .wait()
+        .unwrap();
+    assert_eq!(
+        resp.error.unwrap().error,
+        Some(key_error::Error::Aborted(Aborted {
+            start_ts: 1300 * ms
+        }))
+    );
+
+    // The secondaries are listed while the primary lock is there.
+    let write = msg::Write {
+        key: b"4".to_vec(),
+        value: b"40".to_vec(),
+        op: Op::Put as i32,
+    };
+    let resp = store
+        .prewrite(PrewriteRequest {
+            start_ts: 1400 * ms,
+            primary: write.key.clone(),
+            mutations: vec![write],
+            secondaries: vec![b"5".to_vec()],
+            ..Default::default()
+        })
+        .wait()
+        .unwrap();
+    assert_eq!(resp.error, None);
+    let resp = must_check_txn_status(&store, b"4", 1400 * ms, 1600 * ms);
+    assert_eq!(resp.status, Status::RolledBack as i32);
+    assert_eq!(resp.secondaries, vec![b"5".to_vec()]);
+    let resp = must_check_txn_status(&store, b"4", 1400 * ms, 1600 * ms);
+    assert!(resp.secondaries.is_empty());
+}
+
+#[test]
+fn test_check_txn_status_pushes_min_commit_ts() {
+    let store = MemoryStorage::default();
+    must_commit(&store, b"1", b"10", 1, 2);
+    must_prewrite(&store, b"1", b"11", Op::Put, 10);
+    must_prewrite_secondary(&store, b"2", b"21", b"1", 10);
+
+    // The reader at 20 pushes the live transaction to commit above it, and
+    // reads past its locks from then on.
+    let resp = store
+        .check_txn_status(CheckTxnStatusRequest {
+            primary_key: b"1".to_vec(),
+            lock_ts: 10,
+            caller_start_ts: 20,
+            current_ts: 20,
+        })
+        .wait()
+        .unwrap();
+    assert_eq!(resp.status, Status::Locked as i32);
+    assert_eq!(must_get(&store, b"1", 20), b"10");
+    assert_eq!(must_get(&store, b"2", 20), b"");
+    let resp = store
+        .get(GetRequest {
+            start_ts: 21,
+            key: b"2".to_vec(),
+        })
+        .wait()
+        .unwrap();
+    assert!(resp.error.is_some());
+
+    // A reader at the maximum timestamp cannot push the transaction.
+    let resp = store
+        .check_txn_status(CheckTxnStatusRequest {
+            primary_key: b"1".to_vec(),
+            lock_ts: 10,
+            caller_start_ts: u64::MAX,
+            current_ts: 20,
+        })
+        .wait()
+        .unwrap();
+    assert_eq!(resp.status, Status::Locked as i32);
+
+    let commit = |commit_ts| {
+        store
+            .commit(CommitRequest {
+                is_primary: true,
+                start_ts: 10,
+                commit_ts,
+                keys: vec![b"1".to_vec()],
+            })
+            .wait()
+            .unwrap()
+    };
+    assert!(commit(15).error.is_some());
+    assert_eq!(commit(21).error, None);
+    assert_eq!(must_get(&store, b"1", 20), b"10");
+    assert_eq!(must_get(&store, b"1", 21), b"11");
+}
+
+// Waits for `server` to have served `count` requests, some of which are
+// sent in the background, and checks that it served no more.
+fn wait_for_count(rn: &Network, server: &str, count: usize) {
+    let deadline = Instant::now() + Duration::from_secs(1);
+    while rn.count(server) < count && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(5));
+    }
+    assert_eq!(rn.count(server), count);
+}
+
+fn must_prewrite_secondary(
+    store: &MemoryStorage,
+    key: &[u8],
+    value: &[u8],
+    primary: &[u8],
+    start_ts: u64,
+) {
+    let resp = store
+        .prewrite(PrewriteRequest {
+            start_ts,
+            mutations: vec![msg::Write {
+                key: key.to_vec(),
+                value: value.to_vec(),
+                op: Op::Put as i32,
+            }],
+            primary: primary.to_vec(),
+            ..Default::default()
+        })
+        .wait()
+        .unwrap();
+    assert_eq!(resp.error, None);
+}
+
+fn must_resolve_lock(store: &MemoryStorage, start_ts: u64, commit_ts: u64, keys: Vec<Vec<u8>>) {
+    store
+        .resolve_lock(ResolveLockRequest {
+            start_ts,
+            commit_ts,
+            keys,
+        })
+        .wait()
+        .unwrap();
+}
+
+#[test]
+fn test_resolve_lock() {
+    let store = MemoryStorage::default();
+    must_commit(&store, b"2", b"20", 1, 2);
+
+    // Roll the secondaries of a committed transaction forward.
+    for key in &[b"1", b"2", b"3"] {
+        must_prewrite_secondary(&store, *key, *key, b"1", 10);
+    }
+    must_commit_key(&store, b"1", 10, 11);
+    must_resolve_lock(&store, 10, 11, vec![]);
+    assert_eq!(must_get(&store, b"2", 12), b"2");
+    assert_eq!(must_get(&store, b"3", 12), b"3");
+    assert_eq!(must_get(&store, b"2", 10), b"20");
+
+    // Roll back only the given keys.
+    for key in &[b"1", b"2"] {
+        must_prewrite_secondary(&store, *key, b"0", b"1", 20);
+    }
+    must_resolve_lock(&store, 20, 0, vec![b"2".to_vec()]);
+    assert_eq!(must_get(&store, b"2", 21), b"2");
+    let resp = store
+        .get(GetRequest {
+            start_ts: 21,
+            key: b"1".to_vec(),
+        })
+        .wait()
+        .unwrap();
+    match resp.error.unwrap().error {
+        Some(key_error::Error::Locked(lock)) => {
+            assert_eq!(lock.start_ts, 20);
+            assert_eq!(lock.lock_ttl, 100);
+            assert_eq!(lock.lock_type, Op::Put as i32);
+        }
+        e => panic!("unexpected error {:?}", e),
+    }
+    must_resolve_lock(&store, 20, 0, vec![]);
+    assert_eq!(must_get(&store, b"1", 21), b"1");
+
+    // A reader resolves the locks left by a crashed client.
+    let (_, clients, hook) = init(2);
+    let client0 = clients[0].to_owned();
+    let mut txn0 = client0.begin().unwrap();
+    txn0.set(b"3".to_vec(), b"30".to_vec());
+    txn0.set(b"4".to_vec(), b"40".to_vec());
+    hook.drop_req.store(true, Ordering::Relaxed);
+    hook.fail_primary.store(true, Ordering::Relaxed);
+    assert_eq!(txn0.commit(), Ok(false));
+    hook.drop_req.store(false, Ordering::Relaxed);
+
+    thread::sleep(Duration::from_millis(200));
+    let client1 = clients[1].to_owned();
+    let mut txn1 = client1.begin().unwrap();
+    txn1.set(b"4".to_vec(), b"41".to_vec());
+    assert_eq!(txn1.commit(), Ok(true));
+    let txn1 = client1.begin().unwrap();
+    assert_eq!(txn1.get(b"3".to_vec()), Ok(None));
+    assert_eq!(txn1.get(b"4".to_vec()), Ok(Some(b"41".to_vec())));
+}
+
+#[test]
+fn test_key_error() {
+    let store = MemoryStorage::default();
+    must_commit(&store, b"1", b"10", 10, 20);
+    let write = msg::Write {
+        key: b"1".to_vec(),
+        value: b"11".to_vec(),
+        op: Op::Put as i32,
+    };
+    let resp = store
+        .prewrite(PrewriteRequest {
+            start_ts: 15,
+            mutations: vec![write.clone()],
+            primary: write.key.clone(),
+            ..Default::default()
+        })
+        .wait()
+        .unwrap();
+    assert_eq!(
+        resp.error.unwrap().error,
+        Some(key_error::Error::Conflict(WriteConflict {
+            start_ts: 15,
+            conflict_ts: 20,
+            key: b"1".to_vec(),
+            primary: b"1".to_vec(),
+        }))
+    );
+
+    let resp = store
+        .commit(CommitRequest {
+            is_primary: true,
+            start_ts: 30,
+            commit_ts: 31,
+            keys: vec![write.key],
+        })
+        .wait()
+        .unwrap();
+    assert_eq!(
+        resp.error.unwrap().error,
+        Some(key_error::Error::TxnNotFound(TxnNotFound {
+            start_ts: 30,
+            key: b"1".to_vec(),
+        }))
+    );
+}
+
+#[test]
+fn test_min_commit_ts() {
+    let store = MemoryStorage::default();
+    must_prewrite(&store, b"1", b"10", Op::Put, 10);
+    {
+        let mut kv_data = store.data.lock().unwrap();
+        let (_, lock) = kv_data
+            .read(
+                b"1".to_vec(),
+                Column::Lock,
+                Some(10.into()),
+                Some(10.into()),
+            )
+            .unwrap();
+        let mut lock = lock.unwrap_lock();
+        assert_eq!(lock.min_commit_ts, TimeStamp::from(11));
+        lock.min_commit_ts = 20.into();
+        kv_data.write(b"1".to_vec(), Column::Lock, 10.into(), Value::Lock(lock));
+    }
+
+    let commit = |commit_ts| {
+        store
+            .commit(CommitRequest {
+                is_primary: true,
+                start_ts: 10,
+                commit_ts,
+                keys: vec![b"1".to_vec()],
+            })
+            .wait()
+            .unwrap()
+    };
+    assert_eq!(
+        commit(15).error.unwrap().error,
+        Some(key_error::Error::CommitTsExpired(CommitTsExpired {
+            start_ts: 10,
+            commit_ts: 15,
+            min_commit_ts: 20,
+        }))
+    );
+    assert_eq!(commit(20).error, None);
+    assert_eq!(must_get(&store, b"1", 30), b"10");
+}
+
+#[test]
+fn test_txn_heartbeat() {
+    let store = MemoryStorage::default();
+    let ms = u64::from(TimeStamp::compose(1, 0));
+    must_prewrite(&store, b"1", b"10", Op::Put, 1000 * ms);
+    let resp = store
+        .txn_heartbeat(TxnHeartBeatRequest {
+            primary_key: b"1".to_vec(),
+            start_ts: 1000 * ms,
+            advise_lock_ttl: 300,
+        })
+        .wait()
+        .unwrap();
+    assert_eq!(resp.lock_ttl, 300);
+    let resp = must_check_txn_status(&store, b"1", 1000 * ms, 1200 * ms);
+    assert_eq!(resp.status, Status::Locked as i32);
+    assert_eq!(resp.lock_ttl, 100);
+    let resp = must_check_txn_status(&store, b"1", 1000 * ms, 1400 * ms);
+    assert_eq!(resp.status, Status::RolledBack as i32);
+    let resp = store
+        .txn_heartbeat(TxnHeartBeatRequest {
+            primary_key: b"1".to_vec(),
+            start_ts: 1000 * ms,
+            advise_lock_ttl: 500,
+        })
+        .wait()
+        .unwrap();
+    assert!(resp.error.is_some());
+
+    // The prewrite takes longer than the lock TTL, readers wait for it.
+    let (_, clients, hook) = init(2);
+    let mut client0 = clients[0].to_owned();
+    client0.set_batch_size(1);
+    let mut txn0 = client0.begin().unwrap();
+    txn0.set(b"3".to_vec(), b"30".to_vec());
+    txn0.set(b"4".to_vec(), b"40".to_vec());
+    txn0.set(b"5".to_vec(), b"50".to_vec());
+    hook.delay_secondary_prewrite_ms
+        .store(150, Ordering::Relaxed);
+    let child = thread::spawn(move || txn0.commit());
+
+    thread::sleep(Duration::from_millis(200));
+    let client1 = clients[1].to_owned();
+    let txn1 = client1.begin().unwrap();
+    assert_eq!(txn1.get(b"3".to_vec()), Ok(None));
+    assert_eq!(child.join().unwrap(), Ok(true));
+    hook.delay_secondary_prewrite_ms.store(0, Ordering::Relaxed);
+
+    let txn1 = client1.begin().unwrap();
+    assert_eq!(txn1.get(b"5".to_vec()), Ok(Some(b"50".to_vec())));
+}
+
+#[test]
+fn test_long_lived_transaction() {
+    let (_, clients, hook) = init(2);
+    let mut client0 = clients[0].to_owned();
+    client0.set_batch_size(1);
+    let mut txn0 = client0.begin().unwrap();
+    txn0.set(b"3".to_vec(), b"30".to_vec());
+    txn0.set(b"4".to_vec(), b"40".to_vec());
+    // The transaction has been open for longer than the lock TTL when it
+    // commits, its locks still live past the prewrite.
+    thread::sleep(Duration::from_millis(300));
+    hook.delay_secondary_prewrite_ms
+        .store(300, Ordering::Relaxed);
+    let child = thread::spawn(move || txn0.commit());
+
+    thread::sleep(Duration::from_millis(15));
+    let client1 = clients[1].to_owned();
+    let txn1 = client1.begin().unwrap();
+    assert_eq!(txn1.get(b"3".to_vec()), Ok(None));
+    assert_eq!(child.join().unwrap(), Ok(true));
+    hook.delay_secondary_prewrite_ms.store(0, Ordering::Relaxed);
+
+    let txn1 = client1.begin().unwrap();
+    assert_eq!(txn1.get(b"3".to_vec()), Ok(Some(b"30".to_vec())));
+    assert_eq!(txn1.get(b"4".to_vec()), Ok(Some(b"40".to_vec())));
+}
+
+#[test]
+fn test_lock_record() {
+    let dir = temp_dir("lock-record");
+    let lock = Lock {
+        primary: b"1".to_vec(),
+        kind: WriteKind::Put,
+        ttl: 100,
+        txn_size: 3,
+        min_commit_ts: TimeStamp::from(11),
+        secondaries: vec![b"2".to_vec(), b"3".to_vec()],
+    };
+    {
+        let store = MemoryStorage::new(DiskEngine::open(&dir).unwrap());
+        let resp = store
+            .prewrite(PrewriteRequest {
+                start_ts: 10,
+                mutations: vec![msg::Write {
+                    key: b"1".to_vec(),
+                    value: b"10".to_vec(),
+                    op: Op::Put as i32,
+                }],
+                primary: b"1".to_vec(),
+                lock_ttl: 100,
+                txn_size: 3,
+                secondaries: vec![b"2".to_vec(), b"3".to_vec()],
+            })
+            .wait()
+            .unwrap();
+        assert_eq!(resp.error, None);
+    }
+
+    // The whole record survives a restart.
+    let engine = DiskEngine::open(&dir).unwrap();
+    let (_, value) = engine
+        .read(
+            b"1".to_vec(),
+            Column::Lock,
+            Some(10.into()),
+            Some(10.into()),
+        )
+        .unwrap();
+    assert_eq!(value.unwrap_lock(), lock);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_batch_prewrite_and_commit() {
+    let store = MemoryStorage::default();
+    must_prewrite(&store, b"3", b"30", Op::Put, 10);
+    let mutations: Vec<msg::Write> = (1..5u8)
+        .map(|i| msg::Write {
+            key: vec![b'0' + i],
+            value: vec![i],
+            op: Op::Put as i32,
+        })
+        .collect();
+    // The lock on 3 fails the whole batch.
+    let resp = store
+        .prewrite(PrewriteRequest {
+            start_ts: 20,
+            mutations: mutations.clone(),
+            primary: b"1".to_vec(),
+            ..Default::default()
+        })
+        .wait()
+        .unwrap();
+    assert!(resp.error.is_some());
+    assert_eq!(must_get(&store, b"1", 30), b"");
+    must_resolve_lock(&store, 10, 0, vec![]);
+
+    let resp = store
+        .prewrite(PrewriteRequest {
+            start_ts: 20,
+            mutations,
+            primary: b"1".to_vec(),
+            ..Default::default()
+        })
+        .wait()
+        .unwrap();
+    assert_eq!(resp.error, None);
+    let resp = store
+        .commit(CommitRequest {
+            is_primary: true,
+            start_ts: 20,
+            commit_ts: 21,
+            keys: vec![b"1".to_vec(), b"2".to_vec(), b"3".to_vec(), b"4".to_vec()],
+        })
+        .wait()
+        .unwrap();
+    assert_eq!(resp.error, None);
+    for i in 1..5u8 {
+        assert_eq!(must_get(&store, &[b'0' + i], 30), vec![i]);
+    }
+
+    // Ten keys in batches of four take three prewrites, the commit of the
+    // primary and three commits of the secondaries.
+    let (rn, clients, _) = init(1);
+    let mut client0 = clients[0].to_owned();
+    client0.set_batch_size(4);
+    let mut txn0 = client0.begin().unwrap();
+    for i in 0..10u8 {
+        txn0.set(vec![i], vec![i]);
+    }
+    let count = rn.count("server");
+    assert_eq!(txn0.commit(), Ok(true));
+    // The secondaries are committed in the background.
+    wait_for_count(&rn, "server", count + 7);
+    let txn0 = client0.begin().unwrap();
+    for i in 0..10u8 {
+        assert_eq!(txn0.get(vec![i]), Ok(Some(vec![i])));
+    }
+}
+
+#[test]
+fn test_async_client() {
+    let (rn, _, _) = init(0);
+    let new_client = |name: &str| {
+        let (tso_client, txn_client) = connect(&rn, name);
+        AsyncClient::new(tso_client, txn_client)
+    };
+    let client0 = new_client("async0");
+    let client1 = new_client("async1");
+
+    let committed = client0
+        .begin()
+        .and_then(|mut txn| {
+            txn.set(b"1".to_vec(), b"10".to_vec());
+            txn.set(b"2".to_vec(), b"20".to_vec());
+            txn.commit()
+        })
+        .wait();
+    assert_eq!(committed, Ok(true));
+
+    let txn1 = client1.begin().wait().unwrap();
+    let reads = future::join_all(vec![
+        txn1.get(b"1".to_vec()),
+        txn1.get(b"2".to_vec()),
+        txn1.get(b"3".to_vec()),
+    ]);
+    assert_eq!(
+        reads.wait(),
+        Ok(vec![Some(b"10".to_vec()), Some(b"20".to_vec()), None])
+    );
+    assert_eq!(
+        txn1.scan(vec![], vec![], 0, false).wait(),
+        Ok(vec![
+            (b"1".to_vec(), b"10".to_vec()),
+            (b"2".to_vec(), b"20".to_vec())
+        ])
+    );
+}
+
+#[test]
+fn test_concurrent_transactions() {
+    let (_, clients, _) = init(1);
+    let client = Arc::new(clients[0].to_owned());
+
+    // Two transactions of one client are independent.
+    let mut txn0 = client.begin().unwrap();
+    let mut txn1 = client.begin().unwrap();
+    assert!(txn0.start_ts() < txn1.start_ts());
+    txn0.set(b"1".to_vec(), b"10".to_vec());
+    txn1.set(b"2".to_vec(), b"20".to_vec());
+    assert_eq!(txn1.commit(), Ok(true));
+    assert_eq!(txn0.commit(), Ok(true));
+
+    let mut children = vec![];
+    for i in 0..4u8 {
+        let client = client.clone();
+        children.push(thread::spawn(move || {
+            let mut txn = client.begin().unwrap();
+            txn.set(vec![i], vec![i]);
+            txn.commit()
+        }));
+    }
+    for child in children {
+        assert_eq!(child.join().unwrap(), Ok(true));
+    }
+
+    let txn = client.begin().unwrap();
+    assert_eq!(txn.get(b"1".to_vec()), Ok(Some(b"10".to_vec())));
+    assert_eq!(txn.get(b"2".to_vec()), Ok(Some(b"20".to_vec())));
+    assert_eq!(
+        txn.scan(vec![], vec![4], 0, false),
+        Ok((0..4u8).map(|i| (vec![i], vec![i])).collect())
+    );
+}
+
+#[test]
+fn test_read_your_own_writes() {
+    let (rn, clients, _) = init(2);
+
+    let mut client0 = clients[0].to_owned();
+    let mut txn0 = client0.begin().unwrap();
+    for i in 1..6u8 {
+        txn0.set(vec![i], vec![i]);
+    }
+    let count = rn.count("server");
+    assert_eq!(txn0.commit(), Ok(true));
+    wait_for_count(&rn, "server", count + 3);
+
+    client0.set_batch_size(1);
+    let mut txn0 = client0.begin().unwrap();
+    txn0.set(vec![2], vec![20]);
+    txn0.set(vec![2], vec![21]);
+    txn0.delete(vec![3]);
+    txn0.set(vec![7], vec![70]);
+    assert_eq!(txn0.get(vec![1]), Ok(Some(vec![1])));
+    assert_eq!(txn0.get(vec![2]), Ok(Some(vec![21])));
+    assert_eq!(txn0.get(vec![3]), Ok(None));
+    assert_eq!(txn0.get(vec![7]), Ok(Some(vec![70])));
+    assert_eq!(
+        txn0.scan(vec![], vec![], 0, false),
+        Ok(vec![
+            (vec![1], vec![1]),
+            (vec![2], vec![21]),
+            (vec![4], vec![4]),
+            (vec![5], vec![5]),
+            (vec![7], vec![70]),
+        ])
+    );
+    assert_eq!(
+        txn0.scan(vec![2], vec![], 3, false),
+        Ok(vec![
+            (vec![2], vec![21]),
+            (vec![4], vec![4]),
+            (vec![5], vec![5])
+        ])
+    );
+    assert_eq!(
+        txn0.scan(vec![], vec![5], 2, true),
+        Ok(vec![(vec![4], vec![4]), (vec![2], vec![21])])
+    );
+
+    // The three keys take three prewrites, the commit of the primary and
+    // two commits of the secondaries. Key 2 is written once.
+    let count = rn.count("server");
+    assert_eq!(txn0.commit(), Ok(true));
+    wait_for_count(&rn, "server", count + 6);
+
+    let txn1 = clients[1].begin().unwrap();
+    assert_eq!(txn1.get(vec![2]), Ok(Some(vec![21])));
+    assert_eq!(txn1.get(vec![3]), Ok(None));
+    assert_eq!(txn1.get(vec![7]), Ok(Some(vec![70])));
+}
+
+#[test]
+fn test_read_only_transaction() {
+    let (rn, clients, _) = init(2);
+
+    // Neither an empty nor a read-only transaction sends a request on commit.
+    let client0 = clients[0].to_owned();
+    let txn0 = client0.begin().unwrap();
+    let count = rn.count("server");
+    assert_eq!(txn0.commit(), Ok(true));
+    assert_eq!(rn.count("server"), count);
+    let txn0 = client0.begin().unwrap();
+    assert_eq!(txn0.get(b"1".to_vec()), Ok(None));
+    let count = rn.count("server");
+    assert_eq!(txn0.commit(), Ok(true));
+    assert_eq!(rn.count("server"), count);
+    let txn0 = client0.begin().unwrap();
+    assert_eq!(txn0.rollback(), Ok(()));
+    assert_eq!(rn.count("server"), count);
+
+    let mut txn0 = client0.begin().unwrap();
+    txn0.set(b"1".to_vec(), b"10".to_vec());
+    assert_eq!(txn0.commit(), Ok(true));
+
+    let client1 = clients[1].to_owned();
+    let snapshot = client1.snapshot().unwrap();
+    let mut txn0 = client0.begin().unwrap();
+    txn0.set(b"1".to_vec(), b"11".to_vec());
+    txn0.set(b"2".to_vec(), b"20".to_vec());
+    assert_eq!(txn0.commit(), Ok(true));
+    assert_eq!(snapshot.get(b"1".to_vec()), Ok(Some(b"10".to_vec())));
+    assert_eq!(
+        snapshot.scan(vec![], vec![], 0, false),
+        Ok(vec![(b"1".to_vec(), b"10".to_vec())])
+    );
+    let snapshot = client1.snapshot().unwrap();
+    assert_eq!(snapshot.get(b"1".to_vec()), Ok(Some(b"11".to_vec())));
+}
+
+#[test]
+fn test_client_config() {
+    let policy = RetryPolicy::new(5, 10).max_delay_ms(50);
+    let delays: Vec<u64> = (0..5).map(|i| policy.delay_ms(i)).collect();
+    assert_eq!(delays, vec![10, 20, 40, 50, 50]);
+    let policy = policy.jitter(0.5);
+    for (i, max) in delays.into_iter().enumerate() {
+        let delay = policy.delay_ms(i);
+        assert!(delay >= max / 2 && delay <= max);
+    }
+
+    let (rn, clients, hook) = init(1);
+    let new_client = |name: &str, config: ClientConfig| {
+        let (tso_client, txn_client) = connect(&rn, name);
+        Client::with_config(tso_client, txn_client, config)
+    };
+
+    // The TSO retries give up after the attempts or the deadline are spent.
+    let client = new_client(
+        "tso_attempts",
+        ClientConfig::default().tso_retry(RetryPolicy::new(2, 10)),
+    );
+    rn.enable("tso_attempts_tso", false);
+    assert_eq!(client.get_timestamp(), Err(Error::Timeout));
+    let client = new_client(
+        "tso_deadline",
+        ClientConfig::default().tso_retry(RetryPolicy::new(1000, 10).deadline_ms(200)),
+    );
+    rn.enable("tso_deadline_tso", false);
+    let start = Instant::now();
+    assert_eq!(client.get_timestamp(), Err(Error::Timeout));
+    assert!(start.elapsed() < Duration::from_millis(500));
+
+    // A reader without lock retries fails on the locks of a slow commit.
+    let client0 = clients[0].to_owned();
+    let mut txn0 = client0.begin().unwrap();
+    txn0.set(b"3".to_vec(), b"30".to_vec());
+    txn0.set(b"4".to_vec(), b"40".to_vec());
+    hook.delay_primary_ms.store(200, Ordering::Relaxed);
+    let child = thread::spawn(move || txn0.commit());
+    thread::sleep(Duration::from_millis(50));
+    let client = new_client(
+        "no_lock_retry",
+        ClientConfig::default().lock_retry(RetryPolicy::new(1, 10)),
+    );
+    assert_eq!(client.config().lock_retry.max_attempts, 1);
+    let txn = client.begin().unwrap();
+    assert_eq!(txn.get(b"4".to_vec()), Err(Error::Timeout));
+    let committed = child.join().unwrap();
+    hook.delay_primary_ms.store(0, Ordering::Relaxed);
+    let txn = clients[0].begin().unwrap();
+    let expected = if committed == Ok(true) {
+        b"40".to_vec()
+    } else {
+        Vec::new()
+    };
+    assert_eq!(txn.get(b"4".to_vec()), Ok(Some(expected)));
+}
+
+// A clock that is moved by hand.
+struct MockClock(Arc<AtomicU64>);
+
+impl Clock for MockClock {
+    fn now_ms(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+#[test]
+fn test_time_stamp() {
+    let ts = TimeStamp::compose(1000, 5);
+    assert_eq!((ts.physical(), ts.logical()), (1000, 5));
+    assert!(ts < ts + 1);
+    assert_eq!(ts + 1 - 1, ts);
+    // The logical counter carries into the physical part.
+    let last = TimeStamp::compose(1000, (1 << 18) - 1);
+    assert_eq!(last + 1, TimeStamp::compose(1001, 0));
+
+    let time = time::UNIX_EPOCH + Duration::from_millis(1_500_000);
+    let ts = TimeStamp::from_system_time(time + Duration::from_micros(700));
+    assert_eq!(ts, TimeStamp::compose(1_500_000, 0));
+    assert_eq!(ts.to_system_time(), time);
+
+    let start = TimeStamp::compose(1000, 7);
+    assert_eq!(start.add_ms(30), TimeStamp::compose(1030, 0));
+    assert_eq!(TimeStamp::compose(1030, 2).elapsed_ms_since(start), 30);
+    assert_eq!(start.elapsed_ms_since(TimeStamp::compose(1030, 2)), 0);
+    assert_eq!(u64::from(TimeStamp::from(42)), 42);
+}
+
+#[test]
+fn test_timestamp_oracle() {
+    let now = Arc::new(AtomicU64::new(1000));
+    let tso = TimestampOracle::new(MockClock(now.clone()));
+    let get_ts = |tso: &TimestampOracle<MockClock>| {
+        let res = tso.get_timestamp(TimestampRequest { count: 1 }).wait();
+        TimeStamp::from(res.unwrap().ts)
+    };
+
+    assert_eq!(get_ts(&tso), TimeStamp::compose(1000, 0));
+    assert_eq!(get_ts(&tso), TimeStamp::compose(1000, 1));
+    // The timestamps keep going forward while the clock is behind.
+    now.store(900, Ordering::SeqCst);
+    assert_eq!(get_ts(&tso), TimeStamp::compose(1000, 2));
+    now.store(1001, Ordering::SeqCst);
+    assert_eq!(get_ts(&tso), TimeStamp::compose(1001, 0));
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let ticker = {
+        let now = now.clone();
+        let stop = stop.clone();
+        thread::spawn(move || {
+            for ms in [1100, 1050, 1200, 800, 1300, 1250].iter().cycle() {
+                if stop.load(Ordering::SeqCst) {
+                    return;
+                }
+                now.store(*ms, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(1));
+            }
+        })
+    };
+    let mut children = vec![];
+    for _ in 0..8 {
+        let tso = tso.clone();
+        children.push(thread::spawn(move || {
+            let ts: Vec<TimeStamp> = (0..10000).map(|_| get_ts(&tso)).collect();
+            assert!(ts.windows(2).all(|w| w[0] < w[1]));
+            ts
+        }));
+    }
+    let mut seen = HashSet::new();
+    for child in children {
+        for ts in child.join().unwrap() {
+            assert!(ts.physical() >= 1001);
+            assert!(seen.insert(ts));
+        }
+    }
+    stop.store(true, Ordering::SeqCst);
+    ticker.join().unwrap();
+    assert_eq!(seen.len(), 80000);
+}
+
+#[test]
+fn test_timestamp_oracle_restart() {
+    let dir = temp_dir("tso-restart");
+    let now = Arc::new(AtomicU64::new(10_000));
+    let open = || TimestampOracle::open(&dir, MockClock(now.clone())).unwrap();
+    let get_ts = |tso: &TimestampOracle<MockClock>| {
+        let res = tso.get_timestamp(TimestampRequest { count: 1 }).wait();
+        TimeStamp::from(res.unwrap().ts)
+    };
+
+    let tso = open();
+    let mut last_ts = TimeStamp::ZERO;
+    for _ in 0..100 {
+        last_ts = get_ts(&tso);
+        now.fetch_add(10, Ordering::SeqCst);
+    }
+    assert_eq!(last_ts.physical(), 10_990);
+    drop(tso);
+
+    // The clock has moved backwards across the restart.
+    now.store(5_000, Ordering::SeqCst);
+    let tso = open();
+    let ts = get_ts(&tso);
+    assert!(ts > last_ts);
+    // The first timestamp persisted a limit a window ahead, nothing below
+    // it is handed out since it may have been issued before the restart.
+    assert_eq!(ts, TimeStamp::compose(10_000 + 3000, 1));
+    assert!(get_ts(&tso) > ts);
+
+    // A restart without any timestamp handed out keeps going forward too.
+    drop(tso);
+    let tso = open();
+    assert!(get_ts(&tso) > ts);
+    drop(tso);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_timestamp_range() {
+    let now = Arc::new(AtomicU64::new(1000));
+    let tso = TimestampOracle::new(MockClock(now));
+    let get_ts = |count| {
+        let res = tso.get_timestamp(TimestampRequest { count }).wait();
+        TimeStamp::from(res.unwrap().ts)
+    };
+
+    assert_eq!(get_ts(5), TimeStamp::compose(1000, 0));
+    assert_eq!(get_ts(1), TimeStamp::compose(1000, 5));
+    // Asking for no timestamp hands out one.
+    assert_eq!(get_ts(0), TimeStamp::compose(1000, 6));
+    assert_eq!(get_ts(1), TimeStamp::compose(1000, 7));
+}
+
+// GatedTso holds the first timestamp request at two barriers, the first one
+// passed once the request is in flight and the second one releasing it.
+#[derive(Clone)]
+struct GatedTso {
+    tso: TimestampOracle,
+    first: Arc<AtomicBool>,
+    gate: Arc<(Barrier, Barrier)>,
+}
+
+impl TimestampService for GatedTso {
+    fn get_timestamp(&self, req: TimestampRequest) -> RpcFuture<msg::TimestampResponse> {
+        if self.first.swap(false, Ordering::SeqCst) {
+            self.gate.0.wait();
+            self.gate.1.wait();
+        }
+        self.tso.get_timestamp(req)
+    }
+}
+
+#[test]
+fn test_batched_timestamps() {
+    let rn = Network::new();
+    let gate = Arc::new((Barrier::new(2), Barrier::new(2)));
+    let tso = GatedTso {
+        tso: TimestampOracle::default(),
+        first: Arc::new(AtomicBool::new(true)),
+        gate: gate.clone(),
+    };
+    let mut tso_server_builder = ServerBuilder::new("tso_server".to_owned());
+    add_tso_service(tso, &mut tso_server_builder).unwrap();
+    rn.add_server(tso_server_builder.build());
+    let (tso_client, txn_client) = connect(&rn, "batch");
+    let client = AsyncClient::new(tso_client, txn_client);
+
+    // The first request goes out alone, the others wait for it and share
+    // the next one.
+    let first = client.get_timestamp();
+    gate.0.wait();
+    let rest: Vec<_> = (1..100).map(|_| client.get_timestamp()).collect();
+    gate.1.wait();
+    let ts = future::join_all(iter::once(first).chain(rest).collect::<Vec<_>>())
+        .wait()
+        .unwrap();
+    assert_eq!(rn.count("tso_server"), 2);
+    assert!(ts.windows(2).all(|w| w[0] < w[1]));
+    assert_eq!(ts[99], ts[1] + 98);
+
+    let children: Vec<_> = (0..8)
+        .map(|_| {
+            let client = client.clone();
+            thread::spawn(move || {
+                (0..10)
+                    .map(|_| client.get_timestamp().wait().unwrap())
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect();
+    let mut seen: HashSet<_> = ts.into_iter().collect();
+    for child in children {
+        for ts in child.join().unwrap() {
+            assert!(seen.insert(ts));
+        }
+    }
+    assert!(rn.count("tso_server") < 2 + 80);
+}
+
+// Starts a timestamp oracle of `n` replicas, the one at `i` served as
+// "tso_replica{i}".
+fn init_tso_group(rn: &Network, n: usize) -> (Vec<TsoReplica>, Vec<ElectionTimer>) {
+    let mut replicas = vec![];
+    for i in 0..n {
+        let peers = (0..n)
+            .map(|j| {
+                let name = format!("tso_peer{}_{}", i, j);
+                let cli = rn.create_client(name.clone());
+                rn.enable(&name, true);
+                rn.connect(&name, &format!("tso_replica{}", j));
+                TsoPeerClient::new(cli)
+            })
+            .collect();
+        let replica = TsoReplica::new(i, peers, SystemClock);
+        let mut builder = ServerBuilder::new(format!("tso_replica{}", i));
+        add_tso_service(replica.clone(), &mut builder).unwrap();
+        add_tso_peer_service(replica.clone(), &mut builder).unwrap();
+        rn.add_server(builder.build());
+        replicas.push(replica);
+    }
+    let timers = replicas.iter().map(|r| r.start_election_timer()).collect();
+    (replicas, timers)
+}
+
+// Cuts the replica at `i` off from the other replicas, or reconnects it.
+fn isolate_replica(rn: &Network, n: usize, i: usize, isolated: bool) {
+    for j in (0..n).filter(|&j| j != i) {
+        rn.enable(&format!("tso_peer{}_{}", i, j), !isolated);
+        rn.enable(&format!("tso_peer{}_{}", j, i), !isolated);
+    }
+}
+
+#[test]
+fn test_replicated_timestamp_oracle() {
+    let rn = Network::new();
+    let (replicas, _timers) = init_tso_group(&rn, 3);
+    let tso_clients = (0..3)
+        .map(|i| {
+            let name = format!("client_tso{}", i);
+            let cli = rn.create_client(name.clone());
+            rn.enable(&name, true);
+            rn.connect(&name, &format!("tso_replica{}", i));
+            TSOClient::new(cli)
+        })
+        .collect();
+    let txn_client = TransactionClient::new(rn.create_client("client_txn".to_owned()));
+    let config = ClientConfig::default().tso_retry(RetryPolicy::new(50, 20).max_delay_ms(50));
+    let client = Client::with_tso_replicas(tso_clients, txn_client, config);
+
+    // The first timestamp waits for a leader to be elected.
+    let mut last_ts = client.get_timestamp().unwrap();
+    let leader = (0..3).find(|&i| replicas[i].is_leader()).unwrap();
+
+    // The client still reaches the old leader, which stops handing out
+    // timestamps once its lease runs out. The timestamps keep going forward
+    // across the failover.
+    isolate_replica(&rn, 3, leader, true);
+    let start = Instant::now();
+    while !(0..3).any(|i| i != leader && replicas[i].is_leader()) {
+        let ts = client.get_timestamp().unwrap();
+        assert!(ts > last_ts);
+        last_ts = ts;
+        assert!(start.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(!replicas[leader].is_leader());
+    for _ in 0..10 {
+        let ts = client.get_timestamp().unwrap();
+        assert!(ts > last_ts);
+        last_ts = ts;
+    }
+
+    // The old leader comes back as a follower.
+    isolate_replica(&rn, 3, leader, false);
+    for _ in 0..30 {
+        let ts = client.get_timestamp().unwrap();
+        assert!(ts > last_ts);
+        last_ts = ts;
+        assert!(!replicas[leader].is_leader());
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn test_replicated_timestamp_oracle_restart() {
+    let dir = temp_dir("tso-replica-restart");
+    let rn = Network::new();
+    let now = Arc::new(AtomicU64::new(1000));
+    // A group of one replica, which is its own majority.
+    let open = |name: &str| {
+        let peer = TsoPeerClient::new(rn.create_client(name.to_owned()));
+        TsoReplica::open(0, vec![peer], &dir, MockClock(now.clone())).unwrap()
+    };
+    let get_ts = |replica: &TsoReplica<MockClock>| {
+        let start = Instant::now();
+        while !replica.is_leader() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        let res = replica.get_timestamp(TimestampRequest { count: 1 }).wait();
+        TimeStamp::from(res.unwrap().ts)
+    };
+
+    let replica = open("tso_peer_before");
+    let timer = replica.start_election_timer();
+    let ts = get_ts(&replica);
+    assert_eq!(ts, TimeStamp::compose(1000, 0));
+    drop(timer);
+
+    // The replica resumes above the high-water mark it stored, although
+    // the clock went backwards.
+    now.store(500, Ordering::SeqCst);
+    let replica = open("tso_peer_after");
+    let _timer = replica.start_election_timer();
+    assert!(get_ts(&replica) > ts.add_ms(TSO_WINDOW_MS));
+
+    let _ = fs::remove_dir_all(&dir);
 }
@@ -0,0 +1,439 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use futures::sync::oneshot;
+use futures::{future, Future};
+use labrpc::*;
+
+use crate::config::random_fraction;
+use crate::imp::{load_tso_limit, save_tso_limit};
+use crate::msg::{
+    HwmRequest, HwmResponse, NotLeader, TimestampRequest, TimestampResponse, VoteRequest,
+    VoteResponse,
+};
+use crate::service::{timestamp, tso_peer, TsoPeerClient};
+use crate::{Clock, SystemClock, TimeStamp, TSO_WINDOW_MS};
+
+// The interval between the heartbeats of the leader.
+const HEARTBEAT_INTERVAL_MS: u64 = 30;
+// The leader hands out timestamps for LEASE_MS after a heartbeat that a
+// majority accepted was sent. A replica accepting a heartbeat votes for no
+// one for LEASE_MS after receiving it, so no other leader can be elected in
+// the meantime.
+const LEASE_MS: u64 = 150;
+// A follower hearing nothing from a leader starts an election after a
+// random timeout between ELECTION_TIMEOUT_MS and twice as long, well beyond
+// the lease.
+const ELECTION_TIMEOUT_MS: u64 = 200;
+const TICK_MS: u64 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+struct ReplicaState {
+    term: u64,
+    voted_for: Option<usize>,
+    role: Role,
+    leader: Option<usize>,
+    // The high-water mark stored on this replica. It is persisted in `dir`,
+    // if any, before it is raised, so that it survives a restart by the time
+    // it is accepted or reported in a vote.
+    hwm: TimeStamp,
+    dir: Option<PathBuf>,
+    // The high-water mark the leader knows to be stored on a majority, it
+    // hands out the timestamps below it.
+    limit: TimeStamp,
+    last_ts: TimeStamp,
+    votes: usize,
+    // Until then the leader may hand out timestamps, and the other replicas
+    // refuse to vote.
+    lease_until: Instant,
+    election_deadline: Instant,
+    next_heartbeat: Instant,
+}
+
+impl ReplicaState {
+    fn follow(&mut self, term: u64, leader: Option<usize>) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.election_deadline = election_deadline();
+    }
+
+    // Raises the high-water mark to `hwm`, unless it fails to persist.
+    fn store_hwm(&mut self, hwm: TimeStamp) -> io::Result<()> {
+        if hwm <= self.hwm {
+            return Ok(());
+        }
+        if let Some(dir) = &self.dir {
+            save_tso_limit(dir, hwm)?;
+        }
+        self.hwm = hwm;
+        Ok(())
+    }
+}
+
+fn election_deadline() -> Instant {
+    let timeout = ELECTION_TIMEOUT_MS + (ELECTION_TIMEOUT_MS as f64 * random_fraction()) as u64;
+    Instant::now() + Duration::from_millis(timeout)
+}
+
+// The replies to one high-water mark sent by the leader.
+struct Tally {
+    accepted: usize,
+    rejected: usize,
+    done: Option<oneshot::Sender<Result<()>>>,
+}
+
+// TsoReplica is a member of a replicated timestamp oracle. The replicas
+// elect a leader, which alone hands out timestamps while its lease is
+// valid. Every timestamp is below a high-water mark stored on a majority
+// before it is handed out, and a new leader starts above the highest mark
+// of the majority that voted for it.
+pub struct TsoReplica<C: Clock = SystemClock> {
+    me: usize,
+    // A client for every replica of the group, in the same order on every
+    // replica.
+    peers: Vec<TsoPeerClient>,
+    clock: Arc<C>,
+    state: Arc<Mutex<ReplicaState>>,
+}
+
+impl<C: Clock> Clone for TsoReplica<C> {
+    fn clone(&self) -> Self {
+        TsoReplica {
+            me: self.me,
+            peers: self.peers.clone(),
+            clock: self.clock.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+// ElectionTimer drives the elections and the heartbeats of a replica in
+// the background. It stops when dropped.
+pub struct ElectionTimer {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for ElectionTimer {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl<C: Clock> TsoReplica<C> {
+    // Starts a replica that keeps its high-water mark in memory only.
+    pub fn new(me: usize, peers: Vec<TsoPeerClient>, clock: C) -> TsoReplica<C> {
+        TsoReplica::with_hwm(me, peers, clock, TimeStamp::ZERO, None)
+    }
+
+    // Opens the replica persisted in `dir`, with the high-water mark it
+    // stored before.
+    pub fn open<P: AsRef<Path>>(
+        me: usize,
+        peers: Vec<TsoPeerClient>,
+        dir: P,
+        clock: C,
+    ) -> io::Result<TsoReplica<C>> {
+        let dir = dir.as_ref().to_path_buf();
+        let hwm = load_tso_limit(&dir)?;
+        Ok(TsoReplica::with_hwm(me, peers, clock, hwm, Some(dir)))
+    }
+
+    fn with_hwm(
+        me: usize,
+        peers: Vec<TsoPeerClient>,
+        clock: C,
+        hwm: TimeStamp,
+        dir: Option<PathBuf>,
+    ) -> TsoReplica<C> {
+        let now = Instant::now();
+        TsoReplica {
+            me,
+            peers,
+            clock: Arc::new(clock),
+            state: Arc::new(Mutex::new(ReplicaState {
+                term: 0,
+                voted_for: None,
+                role: Role::Follower,
+                leader: None,
+                hwm,
+                dir,
+                limit: hwm,
+                last_ts: hwm,
+                votes: 0,
+                lease_until: now,
+                election_deadline: election_deadline(),
+                next_heartbeat: now,
+            })),
+        }
+    }
+
+    // Whether the replica is the leader and may hand out timestamps.
+    pub fn is_leader(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.role == Role::Leader && Instant::now() < state.lease_until
+    }
+
+    pub fn start_election_timer(&self) -> ElectionTimer {
+        let (tx, rx) = mpsc::channel();
+        let replica = self.clone();
+        let handle = thread::spawn(move || loop {
+            match rx.recv_timeout(Duration::from_millis(TICK_MS)) {
+                Err(RecvTimeoutError::Timeout) => replica.tick(),
+                _ => return,
+            }
+        });
+        ElectionTimer {
+            stop: Some(tx),
+            handle: Some(handle),
+        }
+    }
+
+    fn majority(&self) -> usize {
+        self.peers.len() / 2 + 1
+    }
+
+    fn tick(&self) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        if state.role == Role::Leader {
+            if now >= state.next_heartbeat {
+                state.next_heartbeat = now + Duration::from_millis(HEARTBEAT_INTERVAL_MS);
+                let (term, hwm) = (state.term, state.hwm);
+                drop(state);
+                // Nobody waits for a heartbeat, it only renews the lease.
+                let _ = self.replicate(term, hwm);
+            }
+        } else if now >= state.election_deadline {
+            state.term += 1;
+            state.role = Role::Candidate;
+            state.voted_for = Some(self.me);
+            state.leader = None;
+            state.votes = 1;
+            state.election_deadline = election_deadline();
+            if state.votes >= self.majority() {
+                self.become_leader(&mut state);
+                return;
+            }
+            let req = VoteRequest {
+                term: state.term,
+                candidate: self.me as u64,
+            };
+            drop(state);
+            for (i, peer) in self.peers.iter().enumerate() {
+                if i == self.me {
+                    continue;
+                }
+                let replica = self.clone();
+                let term = req.term;
+                let vote = peer
+                    .request_vote(&req)
+                    .map(move |res| replica.handle_vote(term, res));
+                peer.spawn(vote.map_err(|_| ()));
+            }
+        }
+    }
+
+    fn handle_vote(&self, term: u64, res: VoteResponse) {
+        let mut state = self.state.lock().unwrap();
+        if res.term > state.term {
+            state.follow(res.term, None);
+            return;
+        }
+        if state.role != Role::Candidate || state.term != term || !res.granted {
+            return;
+        }
+        if state.store_hwm(res.hwm.into()).is_err() {
+            return;
+        }
+        state.votes += 1;
+        if state.votes == self.majority() {
+            self.become_leader(&mut state);
+        }
+    }
+
+    // The leader hands out nothing until a majority accepts its first
+    // heartbeat, and then only above the marks of the replicas that voted.
+    fn become_leader(&self, state: &mut ReplicaState) {
+        state.role = Role::Leader;
+        state.leader = Some(self.me);
+        state.limit = state.hwm;
+        state.last_ts = state.hwm;
+        state.lease_until = Instant::now();
+        state.next_heartbeat = Instant::now();
+    }
+
+    // Stores `hwm` on the replicas as the leader of `term`. Resolves once a
+    // majority accepted it, which also renews the lease from now on, or
+    // fails once a majority cannot.
+    fn replicate(&self, term: u64, hwm: TimeStamp) -> RpcFuture<()> {
+        let sent = Instant::now();
+        let (tx, rx) = oneshot::channel();
+        let tally = Arc::new(Mutex::new(Tally {
+            accepted: 0,
+            rejected: 0,
+            done: Some(tx),
+        }));
+        let stored = self.state.lock().unwrap().store_hwm(hwm).is_ok();
+        self.record(&tally, stored, term, hwm, sent);
+        let req = HwmRequest {
+            term,
+            leader: self.me as u64,
+            hwm: hwm.into(),
+        };
+        for (i, peer) in self.peers.iter().enumerate() {
+            if i == self.me {
+                continue;
+            }
+            let replica = self.clone();
+            let tally = tally.clone();
+            let reply = peer.append_hwm(&req).then(move |res| {
+                let accepted = match res {
+                    Ok(res) => {
+                        let mut state = replica.state.lock().unwrap();
+                        if res.term > state.term {
+                            state.follow(res.term, None);
+                        }
+                        res.accepted
+                    }
+                    Err(_) => false,
+                };
+                replica.record(&tally, accepted, term, hwm, sent);
+                Ok(())
+            });
+            peer.spawn(reply);
+        }
+        Box::new(rx.then(|res| res.unwrap_or_else(|e| Err(Error::Recv(e)))))
+    }
+
+    fn record(
+        &self,
+        tally: &Mutex<Tally>,
+        accepted: bool,
+        term: u64,
+        hwm: TimeStamp,
+        sent: Instant,
+    ) {
+        let mut tally = tally.lock().unwrap();
+        if accepted {
+            tally.accepted += 1;
+        } else {
+            tally.rejected += 1;
+        }
+        if tally.accepted >= self.majority() {
+            if let Some(done) = tally.done.take() {
+                let mut state = self.state.lock().unwrap();
+                if state.role == Role::Leader && state.term == term {
+                    let lease_until = sent + Duration::from_millis(LEASE_MS);
+                    state.lease_until = state.lease_until.max(lease_until);
+                    state.limit = state.limit.max(hwm);
+                }
+                let _ = done.send(Ok(()));
+            }
+        } else if tally.rejected > self.peers.len() - self.majority() {
+            if let Some(done) = tally.done.take() {
+                let _ = done.send(Err(Error::Other("no majority".to_owned())));
+            }
+        }
+    }
+}
+
+impl<C: Clock> timestamp::Service for TsoReplica<C> {
+    fn get_timestamp(&self, req: TimestampRequest) -> RpcFuture<TimestampResponse> {
+        let count = u64::from(req.count.max(1));
+        let mut state = self.state.lock().unwrap();
+        if state.role != Role::Leader || Instant::now() >= state.lease_until {
+            let leader = state.leader.filter(|&leader| leader != self.me);
+            let not_leader = NotLeader {
+                has_leader: leader.is_some(),
+                leader: leader.unwrap_or(0) as u64,
+            };
+            return Box::new(future::ok(TimestampResponse {
+                ts: 0,
+                not_leader: Some(not_leader),
+            }));
+        }
+        let ts = TimeStamp::compose(self.clock.now_ms(), 0).max(state.last_ts + 1);
+        let last_ts = ts + (count - 1);
+        state.last_ts = last_ts;
+        let res = TimestampResponse {
+            ts: ts.into(),
+            not_leader: None,
+        };
+        if last_ts < state.limit {
+            return Box::new(future::ok(res));
+        }
+        // The timestamps are handed out once a mark a window ahead of them is
+        // stored on a majority.
+        let hwm = last_ts.add_ms(TSO_WINDOW_MS);
+        let term = state.term;
+        drop(state);
+        Box::new(self.replicate(term, hwm).map(move |_| res))
+    }
+}
+
+impl<C: Clock> tso_peer::Service for TsoReplica<C> {
+    fn request_vote(&self, req: VoteRequest) -> RpcFuture<VoteResponse> {
+        let mut state = self.state.lock().unwrap();
+        // While the lease of a leader may be valid, candidates are ignored.
+        let leased = Instant::now() < state.lease_until;
+        if !leased && req.term > state.term {
+            state.follow(req.term, None);
+        }
+        let candidate = req.candidate as usize;
+        let granted = !leased
+            && req.term == state.term
+            && state
+                .voted_for
+                .is_none_or(|voted_for| voted_for == candidate);
+        if granted {
+            state.voted_for = Some(candidate);
+            state.election_deadline = election_deadline();
+        }
+        Box::new(future::ok(VoteResponse {
+            term: state.term,
+            granted,
+            hwm: state.hwm.into(),
+        }))
+    }
+
+    fn append_hwm(&self, req: HwmRequest) -> RpcFuture<HwmResponse> {
+        let mut state = self.state.lock().unwrap();
+        if req.term < state.term {
+            return Box::new(future::ok(HwmResponse {
+                term: state.term,
+                accepted: false,
+            }));
+        }
+        state.follow(req.term, Some(req.leader as usize));
+        if state.store_hwm(req.hwm.into()).is_err() {
+            return Box::new(future::ok(HwmResponse {
+                term: state.term,
+                accepted: false,
+            }));
+        }
+        let lease_until = Instant::now() + Duration::from_millis(LEASE_MS);
+        state.lease_until = state.lease_until.max(lease_until);
+        Box::new(future::ok(HwmResponse {
+            term: state.term,
+            accepted: true,
+        }))
+    }
+}